@@ -2,11 +2,22 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 use rocket_csrf::CsrfToken;
-use rocket::{form::Form, Route, http::{uri::Origin, Status}, response::Redirect, futures::TryStreamExt};
-use rocket_db_pools::mongodb::options::FindOptions;
-use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use rocket::{form::Form, Route, State, http::{uri::Origin, Status}, response::Redirect, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::FindOptions};
+use std::collections::HashMap;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils, UserProfile};
+use crate::config::SiteConfig;
+use crate::utils::{excerpt, slugify};
+use crate::comments::{comment_counts, delete_post_comments, post_comments};
+use crate::revisions::{ensure_baseline, record_revision};
+use crate::seo::{Alternate, PageMeta};
+use crate::markdown::{self, TocEntry};
+use crate::related::{adjacent_posts, find_related, related_posts};
+use crate::views::{Visitor, record_view};
+use crate::i18n::Locale;
+use crate::users::{AuthenticatedUser, find_profiles, find_user};
 use rocket_dyn_templates::{Template, context};
-use bson::{self, doc, oid::ObjectId};
+use bson::{self, doc, oid::ObjectId, Document};
 
 pub const BASE: Origin<'static> = uri!("/blog");
 
@@ -15,67 +26,641 @@ struct PostData<'r> {
     authenticity_token: String,
     title: &'r str,
     content: &'r str,
+    // comma separated e.g. "rust, backend"
+    tags: Option<&'r str>,
+    summary: Option<&'r str>,
+    cover_image: Option<&'r str>,
+    lang: Option<&'r str>,
+    // id of the post this is a translation of
+    translation_of: Option<&'r str>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-struct BlogPost {
-    _id: ObjectId,
-    title: String,
-    content: String,
+pub struct BlogPost {
+    pub _id: ObjectId,
+    pub title: String,
+    pub content: String,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
-    published_time: DateTime<Utc>,
-    published_str: String
+    pub published_time: DateTime<Utc>,
+    pub published_str: String,
+    // older posts were saved without these fields, hence the defaults
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_time: Option<bson::DateTime>,
+    // posts written before authorship was recorded have no author
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<PostAuthor>,
+    // short description for listings and meta tags, see excerpt()
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    // image shown when the post is shared, either a path on this site (e.g. an upload) or a full url
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_image: Option<String>,
+    // stable human readable identifier, used to match posts when importing
+    // posts created before slugs existed get one the first time they are exported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    // derived from the content whenever the post is saved, see refresh_stats
+    #[serde(default)]
+    pub word_count: u64,
+    #[serde(default)]
+    pub reading_minutes: u64,
+    #[serde(default)]
+    pub toc: Vec<TocEntry>,
+    // best matches first, worked out on save by related::find_related, None for posts saved before that
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub related_ids: Option<Vec<ObjectId>>,
+    // only ever changed with $inc by views::record_view
+    #[serde(default)]
+    pub view_count: u64,
+    // language of the post, None means the site's default_locale
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    // translations point at the _id of the original post, which is the id of the whole group; None on originals
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation_group: Option<ObjectId>,
+}
+
+// one version of a post in a translation group
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Translation {
+    pub _id: ObjectId,
+    pub title: String,
+    pub lang: Option<String>,
+    pub translation_group: Option<ObjectId>,
+}
+
+// who wrote a post, the username is kept alongside the id so listings can link to the author page
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PostAuthor {
+    pub id: ObjectId,
+    pub username: String,
+}
+
+impl From<&AuthenticatedUser> for PostAuthor {
+    fn from(user: &AuthenticatedUser) -> PostAuthor {
+        PostAuthor { id: user.id, username: user.username.clone() }
+    }
+}
+
+// the parts of a post its writer controls, filled in by both the HTML form and the JSON API
+pub struct PostDraft {
+    pub title: String,
+    pub content: String,
+    pub tags: Vec<String>,
+    // defaults to now when creating, a time in the future schedules the post
+    pub published_time: Option<DateTime<Utc>>,
+    pub summary: Option<String>,
+    pub cover_image: Option<String>,
+    // e.g. "fr", None keeps the current language when editing
+    pub lang: Option<String>,
+    // the post this is a translation of, only used when creating, edits keep the post's group
+    pub translation_group: Option<ObjectId>,
+    // when the post was last edited, None means now, imports set it to keep the exported date
+    pub updated_time: Option<DateTime<Utc>>,
+}
+
+pub const MAX_SUMMARY_LENGTH: usize = 300;
+// length of the excerpt derived from the content of posts without a summary
+pub const EXCERPT_LENGTH: usize = 160;
+
+// treats blank optional form and API fields as missing
+pub fn non_empty(s: Option<&str>) -> Option<String> {
+    s.map(str::trim).filter(|s| !s.is_empty()).map(String::from)
+}
+
+impl PostDraft {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.title.trim().is_empty() {
+            return Err("title cannot be empty");
+        }
+        if self.content.trim().is_empty() {
+            return Err("content cannot be empty");
+        }
+        if self.summary.as_ref().map_or(false, |summary| summary.chars().count() > MAX_SUMMARY_LENGTH) {
+            return Err("summary is too long");
+        }
+        if let Some(cover_image) = &self.cover_image {
+            if !(cover_image.starts_with('/') || cover_image.starts_with("https://") || cover_image.starts_with("http://")) {
+                return Err("cover image must be a path or an http(s) url");
+            }
+        }
+        if let Some(lang) = &self.lang {
+            if lang.is_empty() || lang.len() > 8 || !lang.chars().all(|c| c.is_ascii_lowercase() || c == '-') {
+                return Err("lang must be a lowercase language tag such as en or pt-br");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_published(published_time: &DateTime<Utc>) -> String {
+    format!("{}", published_time.format("%Y-%b-%d"))
+}
+
+impl BlogPost {
+    pub fn from_draft(draft: PostDraft, author: Option<PostAuthor>) -> BlogPost {
+        let published_time = draft.published_time.unwrap_or_else(Utc::now);
+        BlogPost {
+            _id: ObjectId::new(),
+            title: draft.title,
+            content: draft.content,
+            published_str: format_published(&published_time),
+            published_time,
+            tags: draft.tags,
+            updated_time: draft.updated_time.map(bson::DateTime::from_chrono),
+            author,
+            summary: draft.summary,
+            cover_image: draft.cover_image,
+            slug: None,
+            word_count: 0,
+            reading_minutes: 0,
+            toc: Vec::new(),
+            related_ids: None,
+            view_count: 0,
+            lang: draft.lang,
+            translation_group: draft.translation_group,
+        }.with_stats()
+    }
+
+    // edits keep the original publish time unless the draft sets a new one
+    fn apply(&mut self, draft: PostDraft) {
+        self.title = draft.title;
+        self.content = draft.content;
+        self.tags = draft.tags;
+        self.summary = draft.summary;
+        self.cover_image = draft.cover_image;
+        if draft.lang.is_some() {
+            self.lang = draft.lang;
+        }
+        if let Some(published_time) = draft.published_time {
+            self.published_time = published_time;
+            self.published_str = format_published(&published_time);
+        }
+        self.updated_time = Some(draft.updated_time.map_or_else(bson::DateTime::now, bson::DateTime::from_chrono));
+        self.refresh_stats();
+    }
+
+    fn with_stats(mut self) -> BlogPost {
+        self.refresh_stats();
+        self
+    }
+
+    // word count, reading time and table of contents of the current content
+    pub fn refresh_stats(&mut self) {
+        self.word_count = markdown::word_count(&self.content);
+        self.reading_minutes = markdown::reading_minutes(self.word_count);
+        self.toc = markdown::table_of_contents(&self.content);
+    }
+
+    pub fn id(&self) -> String {
+        self._id.to_hex()
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.published_time <= Utc::now()
+    }
+
+    // when the post last changed (falls back to when it was published)
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.updated_time.map(|t| t.to_chrono()).unwrap_or(self.published_time)
+    }
+
+    // the original post and all of its translations share this id
+    pub fn group_id(&self) -> ObjectId {
+        self.translation_group.unwrap_or(self._id)
+    }
+
+    pub fn lang_or<'a>(&'a self, default_locale: &'a str) -> &'a str {
+        self.lang.as_deref().unwrap_or(default_locale)
+    }
+
+    // the summary if the writer gave one, otherwise the start of the content
+    pub fn excerpt(&self) -> String {
+        match &self.summary {
+            Some(summary) => summary.clone(),
+            None => excerpt(&self.content, EXCERPT_LENGTH),
+        }
+    }
+}
+
+// persistence shared by the HTML routes below and blog_api.rs
+
+// the post's author and admins can edit and delete it and see and restore its history
+pub fn can_manage(user: &AuthenticatedUser, post: &BlogPost) -> bool {
+    user.admin || post.author.as_ref().map_or(false, |author| author.id == user.id)
+}
+
+pub async fn find_post(db: &mongodb::Client, id: ObjectId) -> mongodb::error::Result<Option<BlogPost>> {
+    let mut post = db.posts_coll().find_one(doc! {"_id": id}, None).await?;
+    // posts saved before stats were recorded get them filled in until their next save
+    if let Some(post) = post.as_mut().filter(|post| post.word_count == 0) {
+        post.refresh_stats();
+    }
+    Ok(post)
+}
+
+// the slug made from `title`, with -2, -3, ... appended if another post already uses it
+pub async fn unique_slug(db: &mongodb::Client, title: &str) -> mongodb::error::Result<String> {
+    let base = match slugify(title) {
+        slug if slug.is_empty() => "post".to_string(),
+        slug => slug,
+    };
+    let mut slug = base.clone();
+    let mut n = 1;
+    while db.posts_coll().count_documents(doc! {"slug": &slug}, None).await? > 0 {
+        n += 1;
+        slug = format!("{base}-{n}");
+    }
+    Ok(slug)
+}
+
+// every save also writes a revision, see revisions.rs
+pub async fn create_post(db: &mongodb::Client, draft: PostDraft, author: PostAuthor) -> mongodb::error::Result<BlogPost> {
+    let mut post = BlogPost::from_draft(draft, Some(author));
+    post.slug = Some(unique_slug(db, &post.title).await?);
+    post.related_ids = Some(find_related(db, &post).await?);
+    db.posts_coll().insert_one(&post, None).await?;
+    record_revision(db, &post, post.author.as_ref(), None).await?;
+    Ok(post)
+}
+
+// None if there is no post with this id
+pub async fn update_post(db: &mongodb::Client, id: ObjectId, draft: PostDraft, editor: PostAuthor) -> mongodb::error::Result<Option<BlogPost>> {
+    save_edit(db, id, draft, Some(editor), None).await
+}
+
+// restored_from is the revision whose content is being brought back, if any
+// editor is None for edits not made by a user, such as imports
+pub async fn save_edit(db: &mongodb::Client, id: ObjectId, draft: PostDraft, editor: Option<PostAuthor>, restored_from: Option<ObjectId>) -> mongodb::error::Result<Option<BlogPost>> {
+    let mut post = match find_post(db, id).await? {
+        Some(post) => post,
+        None => return Ok(None),
+    };
+    ensure_baseline(db, &post).await?;
+    post.apply(draft);
+    post.related_ids = Some(find_related(db, &post).await?);
+    // replaces the post but keeps the view count as it is in the database, views may have been counted since we read it
+    let replacement = bson::to_document(&post)?;
+    let pipeline = vec![doc! {"$replaceWith": {"$mergeObjects": [{"$literal": replacement}, {"view_count": {"$ifNull": ["$view_count", 0]}}]}}];
+    db.posts_coll().update_one(doc! {"_id": id}, pipeline, None).await?;
+    record_revision(db, &post, editor.as_ref(), restored_from).await?;
+    Ok(Some(post))
+}
+
+// false if there was no post with this id, comments on the post are deleted along with it
+pub async fn delete_post(db: &mongodb::Client, id: ObjectId) -> mongodb::error::Result<bool> {
+    let deleted = db.posts_coll().delete_one(doc! {"_id": id}, None).await?.deleted_count == 1;
+    if deleted {
+        delete_post_comments(db, id).await?;
+    }
+    Ok(deleted)
+}
+
+// lowercases, trims and dedups user supplied tags
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = Vec::new();
+    for tag in tags.split(',').map(|tag| tag.trim().to_lowercase()) {
+        if !tag.is_empty() && !parsed.contains(&tag) {
+            parsed.push(tag);
+        }
+    }
+    parsed
+}
+
+// posts with a published_time in the future are scheduled and hidden until then
+pub fn published_filter(tag: Option<&str>) -> Document {
+    let mut filter = doc! {"published_time": {"$lte": bson::DateTime::now()}};
+    if let Some(tag) = tag {
+        filter.insert("tags", tag);
+    }
+    filter
+}
+
+// published posts by one author
+pub fn author_filter(author_id: ObjectId) -> Document {
+    let mut filter = published_filter(None);
+    filter.insert("author.id", author_id);
+    filter
+}
+
+// display names and bios of the authors of these posts, keyed by username
+pub async fn post_authors(db: &mongodb::Client, posts: &[BlogPost]) -> mongodb::error::Result<HashMap<String, UserProfile>> {
+    let mut usernames: Vec<String> = posts.iter().filter_map(|post| post.author.as_ref()).map(|author| author.username.clone()).collect();
+    usernames.sort();
+    usernames.dedup();
+    let mut profiles = find_profiles(db, usernames.clone()).await?;
+    // authors whose accounts were removed are shown by username
+    for username in usernames {
+        profiles.entry(username.clone()).or_insert(UserProfile { display_name: username.clone(), username, bio: String::new() });
+    }
+    Ok(profiles)
+}
+
+// listings show each post once, in the reader's language where possible (see localize_posts)
+pub fn originals_only(mut filter: Document) -> Document {
+    filter.insert("translation_group", bson::Bson::Null);
+    filter
+}
+
+// every published version of the post's translation group, the post itself included
+pub async fn find_translations(db: &mongodb::Client, post: &BlogPost) -> mongodb::error::Result<Vec<Translation>> {
+    let group = post.group_id();
+    let mut filter = published_filter(None);
+    filter.insert("$or", vec![doc! {"_id": group}, doc! {"translation_group": group}]);
+    let find_options = FindOptions::builder().projection(doc! {"title": 1, "lang": 1, "translation_group": 1}).sort(doc! {"_id": 1}).build();
+    db.posts_coll().clone_with_type::<Translation>().find(filter, find_options).await?.try_collect().await
+}
+
+// swaps listed originals for their published translation into lang where there is one
+pub async fn localize_posts(db: &mongodb::Client, posts: Vec<BlogPost>, lang: &str, default_locale: &str) -> mongodb::error::Result<Vec<BlogPost>> {
+    let groups: Vec<ObjectId> = posts.iter().filter(|post| post.lang_or(default_locale) != lang).map(|post| post._id).collect();
+    if groups.is_empty() {
+        return Ok(posts);
+    }
+    let mut filter = published_filter(None);
+    filter.insert("translation_group", doc! {"$in": groups});
+    filter.insert("lang", lang);
+    let translations: Vec<BlogPost> = db.posts_coll().find(filter, None).await?.try_collect().await?;
+    let mut translations: HashMap<ObjectId, BlogPost> = translations.into_iter().map(|post| (post.group_id(), post)).collect();
+    Ok(posts.into_iter().map(|post| translations.remove(&post._id).unwrap_or(post)).collect())
+}
+
+// newest first, with _id as a tie breaker so that paging is stable
+pub fn newest_first() -> Document {
+    doc! {"published_time": -1, "_id": -1}
+}
+
+// newest published posts first, optionally restricted to a tag
+pub async fn recent_posts(db: &mongodb::Client, tag: Option<&str>, limit: i64) -> mongodb::error::Result<Vec<BlogPost>> {
+    let find_options = FindOptions::builder().limit(limit).sort(newest_first()).build();
+    db.posts_coll().find(published_filter(tag), find_options).await?.try_collect().await
+}
+
+// page numbers and counts handed to templates
+#[derive(Debug, Serialize)]
+pub struct Pagination {
+    pub page: u64,
+    pub page_size: u64,
+    pub total: u64,
+    pub pages: u64,
+    pub previous: Option<u64>,
+    pub next: Option<u64>,
+    // page numbers worth linking to, the current page and a few on either side
+    pub links: Vec<u64>,
+}
+
+const PAGE_LINKS_AROUND: u64 = 5;
+
+impl Pagination {
+    pub fn new(page: u64, page_size: u64, total: u64) -> Pagination {
+        let pages = total.div_ceil(page_size);
+        Pagination {
+            page,
+            page_size,
+            total,
+            pages,
+            previous: if page > 1 { Some(page - 1) } else { None },
+            next: if page < pages { Some(page + 1) } else { None },
+            links: (page.saturating_sub(PAGE_LINKS_AROUND).max(1)..=page.saturating_add(PAGE_LINKS_AROUND).min(pages)).collect(),
+        }
+    }
+}
+
+// numbered pages for the HTML listing, skip/limit is fine for the first few pages people click through
+// filter is usually published_filter with extra conditions
+pub async fn posts_page(db: &mongodb::Client, filter: Document, page: u64, page_size: u64) -> mongodb::error::Result<(Vec<BlogPost>, Pagination)> {
+    let page_size = page_size.max(1);
+    let page = page.clamp(1, u64::MAX / page_size);
+    let total = db.posts_coll().count_documents(filter.clone(), None).await?;
+    let find_options = FindOptions::builder()
+        .limit(page_size as i64)
+        .skip((page - 1) * page_size)
+        .sort(newest_first())
+        .build();
+    let posts = db.posts_coll().find(filter, find_options).await?.try_collect().await?;
+    Ok((posts, Pagination::new(page, page_size, total)))
+}
+
+/// Position of a post in the newest first ordering, used for keyset (cursor) pagination
+/// so that fetching the next page is constant-time no matter how deep it is.
+/// Serialized as `<published_time millis>_<object id hex>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostCursor {
+    published_time: bson::DateTime,
+    id: ObjectId,
+}
+
+impl PostCursor {
+    pub fn of(post: &BlogPost) -> PostCursor {
+        PostCursor { published_time: post.published_time.into(), id: post._id }
+    }
+
+    pub fn parse(cursor: &str) -> Option<PostCursor> {
+        let (millis, id) = cursor.split_once('_')?;
+        Some(PostCursor {
+            published_time: bson::DateTime::from_millis(millis.parse().ok()?),
+            id: ObjectId::parse_str(id).ok()?,
+        })
+    }
+
+    // everything strictly after this cursor in newest first order
+    pub fn after_filter(&self) -> Document {
+        doc! {"$or": [
+            {"published_time": {"$lt": self.published_time}},
+            {"published_time": self.published_time, "_id": {"$lt": self.id}},
+        ]}
+    }
+
+    // everything strictly before this cursor in newest first order
+    pub fn before_filter(&self) -> Document {
+        doc! {"$or": [
+            {"published_time": {"$gt": self.published_time}},
+            {"published_time": self.published_time, "_id": {"$gt": self.id}},
+        ]}
+    }
+}
+
+impl std::fmt::Display for PostCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}", self.published_time.timestamp_millis(), self.id.to_hex())
+    }
+}
+
+// up to `limit` posts after the cursor (or from the start) plus the cursor of the next page if there is one
+pub async fn posts_after(db: &mongodb::Client, filter: Document, cursor: Option<&PostCursor>, limit: i64) -> mongodb::error::Result<(Vec<BlogPost>, Option<PostCursor>)> {
+    let filter = match cursor {
+        Some(cursor) => doc! {"$and": [filter, cursor.after_filter()]},
+        None => filter,
+    };
+    // fetch one extra post to find out whether there is another page
+    let find_options = FindOptions::builder().limit(limit + 1).sort(newest_first()).build();
+    let mut posts: Vec<BlogPost> = db.posts_coll().find(filter, find_options).await?.try_collect().await?;
+    let mut next_cursor = None;
+    if posts.len() as i64 > limit {
+        posts.truncate(limit as usize);
+        next_cursor = posts.last().map(PostCursor::of);
+    }
+    Ok((posts, next_cursor))
 }
 
 #[get("/?<page>")]
-async fn blog_posts(db: Connection<MainDatabase>, page: Option<u64>) -> Template {
-    let posts_coll = db.app_db().collection::<BlogPost>("posts");
-    let post_to_skip = (page.unwrap_or(1).clamp(1, u64::MAX / 50 + 1) - 1) * 50;
-    let find_options = FindOptions::builder().limit(50).skip(post_to_skip).sort(doc! {"published_time": -1}).build();
-    let cursor = posts_coll.find(None, find_options).await.unwrap();
-    let posts: Vec<BlogPost> = cursor.try_collect().await.unwrap();
+async fn blog_posts(db: Connection<MainDatabase>, config: &State<SiteConfig>, locale: Locale, page: Option<u64>) -> Result<Template, Status> {
+    let (posts, pagination) = posts_page(&db, originals_only(published_filter(None)), page.unwrap_or(1), config.page_size).await.map_err(|_e| Status::InternalServerError)?;
+    let posts = localize_posts(&db, posts, &locale.lang, &config.default_locale).await.map_err(|_e| Status::InternalServerError)?;
+    let comment_counts = comment_counts(&db, &posts).await.map_err(|_e| Status::InternalServerError)?;
+    let authors = post_authors(&db, &posts).await.map_err(|_e| Status::InternalServerError)?;
+    let meta = PageMeta::listing(config, &config.site_name, format!("The latest posts on {}", config.site_name), BASE, pagination.page);
     // can probably do html generation on Rust side to avoid another iteration
-    Template::render("blog/index", context! {posts})
+    Ok(Template::render("blog/index", context! {posts, pagination, comment_counts, authors, meta, lang: locale.lang, locales: locale.locales}))
+}
+
+// /blog/tags/rust?page=2
+#[get("/tags/<tag>?<page>")]
+pub async fn tag_posts(tag: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>, locale: Locale, page: Option<u64>) -> Result<Template, Status> {
+    let tag = tag.to_lowercase();
+    let (posts, pagination) = posts_page(&db, originals_only(published_filter(Some(&tag))), page.unwrap_or(1), config.page_size).await.map_err(|_e| Status::InternalServerError)?;
+    let posts = localize_posts(&db, posts, &locale.lang, &config.default_locale).await.map_err(|_e| Status::InternalServerError)?;
+    let comment_counts = comment_counts(&db, &posts).await.map_err(|_e| Status::InternalServerError)?;
+    let authors = post_authors(&db, &posts).await.map_err(|_e| Status::InternalServerError)?;
+    let meta = PageMeta::listing(config, format!("Posts tagged #{tag}"), format!("Posts tagged #{tag} on {}", config.site_name), uri!(BASE, tag_posts(tag.as_str(), _)), pagination.page);
+    Ok(Template::render("blog/index", context! {posts, pagination, comment_counts, authors, tag, meta, lang: locale.lang, locales: locale.locales}))
+}
+
+// /blog/authors/elijah?page=2
+#[get("/authors/<username>?<page>")]
+async fn author_posts(username: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>, locale: Locale, page: Option<u64>) -> Result<Template, Status> {
+    let author = find_user(&db, username).await.map_err(|_e| Status::InternalServerError)?.ok_or(Status::NotFound)?;
+    let (posts, pagination) = posts_page(&db, originals_only(author_filter(author.id())), page.unwrap_or(1), config.page_size).await.map_err(|_e| Status::InternalServerError)?;
+    let posts = localize_posts(&db, posts, &locale.lang, &config.default_locale).await.map_err(|_e| Status::InternalServerError)?;
+    let comment_counts = comment_counts(&db, &posts).await.map_err(|_e| Status::InternalServerError)?;
+    let profile = author.profile();
+    let description = if profile.bio.is_empty() { format!("Posts by {}", profile.display_name) } else { excerpt(&profile.bio, EXCERPT_LENGTH) };
+    let meta = PageMeta::listing(config, &profile.display_name, description, uri!(BASE, author_posts(username, _)), pagination.page);
+    Ok(Template::render("blog/author", context! {author: profile, posts, pagination, comment_counts, meta, lang: locale.lang}))
 }
 
 #[get("/posts/<id>", rank=2)]
-async fn blog_post(id: &str, db: Connection<MainDatabase>) -> Result<Template, Status> {
-    let posts_coll = db.app_db().collection::<BlogPost>("posts");
+pub async fn blog_post(id: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>, user: Option<AuthenticatedUser>, visitor: Visitor, locale: Locale, csrf_token: Option<CsrfToken>) -> Result<Template, Status> {
     let oid = ObjectId::parse_str(&id).map_err(|_e| Status::NotFound)?;
-    match posts_coll.find_one(doc!{"_id": oid}, None).await.unwrap() {
-        Some(post) => Ok(Template::render("blog/post", context!{post})),
-        _ => Err(Status::NotFound)
+    let mut post = match find_post(&db, oid).await.map_err(|_e| Status::InternalServerError)? {
+        Some(post) if post.is_published() => post,
+        _ => return Err(Status::NotFound)
+    };
+    // counting is best-effort, a failed write shouldn't stop the post from showing
+    if let Err(error) = record_view(&db, &post, &visitor).await {
+        println!("{error:?}");
+    }
+    let comments = post_comments(&db, oid).await.map_err(|_e| Status::InternalServerError)?;
+    let can_edit = user.as_ref().map_or(false, |user| user.admin || post.author.as_ref().map_or(false, |author| author.id == user.id));
+    let author = match &post.author {
+        Some(author) => find_user(&db, &author.username).await.map_err(|_e| Status::InternalServerError)?.map(|user| user.profile()),
+        None => None,
+    };
+    let (previous, next) = adjacent_posts(&db, &post).await.map_err(|_e| Status::InternalServerError)?;
+    // posts saved before related posts were stored get them worked out once here
+    if post.related_ids.is_none() {
+        let related_ids = find_related(&db, &post).await.map_err(|_e| Status::InternalServerError)?;
+        db.posts_coll().update_one(doc! {"_id": post._id}, doc! {"$set": {"related_ids": related_ids.clone()}}, None).await
+            .map_err(|_e| Status::InternalServerError)?;
+        post.related_ids = Some(related_ids);
+    }
+    let related = related_posts(&db, &post).await.map_err(|_e| Status::InternalServerError)?;
+    let translations = find_translations(&db, &post).await.map_err(|_e| Status::InternalServerError)?;
+    let mut meta = PageMeta::for_post(config, &post, author.as_ref());
+    if translations.len() > 1 {
+        meta.alternates = translations.iter().map(|translation| Alternate {
+            hreflang: translation.lang.clone().unwrap_or_else(|| config.default_locale.clone()),
+            url: config.absolute_url(uri!(BASE, blog_post(translation._id.to_hex()))),
+        }).collect();
     }
+    let translations: Vec<Translation> = translations.into_iter()
+        .filter(|translation| translation._id != post._id)
+        .map(|mut translation| {
+            translation.lang.get_or_insert_with(|| config.default_locale.clone());
+            translation
+        })
+        .collect();
+    Ok(Template::render("blog/post", context!{
+        meta,
+        translations,
+        lang: locale.lang,
+        previous,
+        next,
+        related,
+        content_html: markdown::render(&post.content),
+        post,
+        author,
+        comments,
+        can_edit,
+        username: user.map(|user| user.username),
+        authenticity_token: csrf_token.map(|token| token.authenticity_token()),
+    }))
 }
 
-#[get("/new-post", rank=1)]
-fn new_blog_post(csrf_token: CsrfToken) -> Template {
+// /blog/new-post?translation_of=<post id> writes a translation of an existing post
+// anyone with an account can write posts, visitors are sent to the login page by the AuthenticatedUser guard
+#[get("/new-post?<translation_of>", rank=1)]
+fn new_blog_post(csrf_token: CsrfToken, _user: AuthenticatedUser, locale: Locale, translation_of: Option<&str>) -> Template {
     Template::render("blog/new_post", context! {
         authenticity_token: csrf_token.authenticity_token(),
+        translation_of,
+        lang: locale.lang,
+        locales: locale.locales,
     })
 }
 
-#[get("/new-post", rank=2)]
-fn new_blog_post_redirect() -> Redirect {
-    Redirect::to(uri!(BASE, new_blog_post()))
+#[get("/new-post?<translation_of>", rank=2)]
+fn new_blog_post_redirect(translation_of: Option<&str>) -> Redirect {
+    Redirect::to(uri!(BASE, new_blog_post(translation_of)))
 }
 
 #[post("/new-post", data = "<form>")]
-async fn new_blog_post_api(form: Form<PostData<'_>>, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+async fn new_blog_post_api(form: Form<PostData<'_>>, csrf_token: CsrfToken, user: AuthenticatedUser, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Redirect, Status> {
     if csrf_token.verify(&form.authenticity_token).is_err() {
         return Err(Status::Unauthorized);
     }
-    let posts = db.database("app_name").collection("posts");
-    let published_time: DateTime<Utc> = Utc::now().into();
-    let published_str = format!("{}", published_time.format("%Y-%b-%d"));
-    let _insert_result = posts.insert_one(
-        bson::to_document(
-            &BlogPost{_id: ObjectId::new(), title: form.title.into(), content: form.content.into(), published_time, published_str}
-        ).unwrap(), None).await.map_err(|_e| Status::InternalServerError)?;
+    let mut draft = PostDraft {
+        title: form.title.into(),
+        content: form.content.into(),
+        tags: form.tags.map(parse_tags).unwrap_or_default(),
+        published_time: None,
+        summary: non_empty(form.summary),
+        cover_image: non_empty(form.cover_image),
+        lang: non_empty(form.lang).map(|lang| lang.to_lowercase()),
+        translation_group: None,
+        updated_time: None,
+    };
+    draft.validate().map_err(|_e| Status::UnprocessableEntity)?;
+    // written with the post itself so a translation is never saved without its group
+    draft.translation_group = match non_empty(form.translation_of) {
+        Some(original) => {
+            let original = ObjectId::parse_str(&original).map_err(|_e| Status::UnprocessableEntity)?;
+            let original = find_post(&db, original).await.map_err(|_e| Status::InternalServerError)?.ok_or(Status::UnprocessableEntity)?;
+            // a translation joins the original's group, so it takes the same rights as editing it
+            if !can_manage(&user, &original) {
+                return Err(Status::Forbidden);
+            }
+            let group = original.group_id();
+            // one version per language
+            let lang = draft.lang.as_deref().ok_or(Status::UnprocessableEntity)?;
+            let existing = db.posts_coll().clone_with_type::<Translation>()
+                .find(doc! {"$or": [{"_id": group}, {"translation_group": group}]}, None).await
+                .map_err(|_e| Status::InternalServerError)?
+                .try_collect::<Vec<Translation>>().await.map_err(|_e| Status::InternalServerError)?;
+            if existing.iter().any(|translation| translation.lang.as_deref().unwrap_or(&config.default_locale) == lang) {
+                return Err(Status::Conflict);
+            }
+            Some(group)
+        },
+        None => None,
+    };
+    create_post(&db, draft, PostAuthor::from(&user)).await.map_err(|_e| Status::InternalServerError)?;
     // TODO: flash a success
     Ok(Redirect::to(uri!(BASE, blog_posts(Some(1)))))
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![blog_posts, blog_post, new_blog_post, new_blog_post_redirect, new_blog_post_api]
+    routes![blog_posts, tag_posts, author_posts, blog_post, new_blog_post, new_blog_post_redirect, new_blog_post_api]
 }
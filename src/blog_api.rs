@@ -0,0 +1,131 @@
+// JSON API for the blog, shares its queries with the HTML routes in blog.rs
+use rocket::{Route, State, http::{uri::Origin, Status}};
+use rocket::response::status::Created;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use bson::oid::ObjectId;
+use rocket_db_pools::mongodb;
+use crate::blog::{self, BlogPost, PostAuthor, PostCursor, PostDraft, can_manage, posts_after, published_filter, find_post, create_post, update_post, delete_post};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase};
+use crate::errors::{ApiError, ApiResult};
+use crate::users::AuthenticatedUser;
+
+pub const BASE: Origin<'static> = uri!("/api/blog");
+pub const MAX_LIMIT: u64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![list_posts, get_post, create_post_api, update_post_api, delete_post_api]
+}
+
+#[derive(Serialize)]
+struct PostList {
+    posts: Vec<BlogPost>,
+    // pass back as ?cursor= to get the next page, null on the last page
+    next_cursor: Option<String>,
+}
+
+// request body for creating and replacing posts
+#[derive(Deserialize)]
+struct PostInput {
+    title: String,
+    content: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    // RFC 3339 e.g. 2023-10-01T12:00:00Z
+    published_time: Option<String>,
+    summary: Option<String>,
+    // a path such as /blog/uploads/<id> or a full url
+    cover_image: Option<String>,
+    // e.g. "fr", defaults to the site's default_locale
+    lang: Option<String>,
+}
+
+impl TryFrom<PostInput> for PostDraft {
+    type Error = ApiError;
+
+    fn try_from(input: PostInput) -> Result<PostDraft, ApiError> {
+        let published_time = match input.published_time {
+            Some(time) => Some(DateTime::parse_from_rfc3339(&time)
+                .map_err(|_e| ApiError::new(Status::UnprocessableEntity, "published_time must be an RFC 3339 timestamp"))?
+                .with_timezone(&Utc)),
+            None => None,
+        };
+        let draft = PostDraft {
+            title: input.title,
+            content: input.content,
+            tags: blog::parse_tags(&input.tags.join(",")),
+            published_time,
+            summary: blog::non_empty(input.summary.as_deref()),
+            cover_image: blog::non_empty(input.cover_image.as_deref()),
+            lang: blog::non_empty(input.lang.as_deref()).map(|lang| lang.to_lowercase()),
+            translation_group: None,
+            updated_time: None,
+        };
+        draft.validate().map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+        Ok(draft)
+    }
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, ApiError> {
+    ObjectId::parse_str(id).map_err(|_e| ApiError::not_found())
+}
+
+// a post the user may change, see blog::can_manage
+async fn managed_post(db: &mongodb::Client, id: &str, user: &AuthenticatedUser) -> Result<BlogPost, ApiError> {
+    let post = find_post(db, parse_id(id)?).await?.ok_or_else(ApiError::not_found)?;
+    if !can_manage(user, &post) {
+        return Err(ApiError::new(Status::Forbidden, "only the author or an admin can change this post"));
+    }
+    Ok(post)
+}
+
+// /api/blog/posts?tag=rust&author=elijah&limit=20&cursor=1690000000000_64c...
+#[get("/posts?<tag>&<author>&<limit>&<cursor>")]
+async fn list_posts(tag: Option<&str>, author: Option<&str>, limit: Option<u64>, cursor: Option<&str>, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> ApiResult<PostList> {
+    let cursor = match cursor {
+        Some(cursor) => Some(PostCursor::parse(cursor).ok_or_else(|| ApiError::new(Status::BadRequest, "invalid cursor"))?),
+        None => None,
+    };
+    let tag = tag.map(|tag| tag.to_lowercase());
+    let mut filter = published_filter(tag.as_deref());
+    if let Some(author) = author {
+        filter.insert("author.username", author);
+    }
+    let limit = limit.unwrap_or(config.page_size).clamp(1, MAX_LIMIT);
+    let (posts, next_cursor) = posts_after(&db, filter, cursor.as_ref(), limit as i64).await?;
+    Ok(Json(PostList { posts, next_cursor: next_cursor.map(|cursor| cursor.to_string()) }))
+}
+
+#[get("/posts/<id>")]
+async fn get_post(id: &str, db: Connection<MainDatabase>) -> ApiResult<BlogPost> {
+    match find_post(&db, parse_id(id)?).await? {
+        Some(post) if post.is_published() => Ok(Json(post)),
+        _ => Err(ApiError::not_found()),
+    }
+}
+
+#[post("/posts", data = "<input>")]
+async fn create_post_api(input: Json<PostInput>, user: AuthenticatedUser, db: Connection<MainDatabase>) -> Result<Created<Json<BlogPost>>, ApiError> {
+    let post = create_post(&db, input.into_inner().try_into()?, PostAuthor::from(&user)).await?;
+    let location = uri!(BASE, get_post(post.id())).to_string();
+    Ok(Created::new(location).body(Json(post)))
+}
+
+// replaces the title, content and tags of a post, only its author or an admin can
+#[put("/posts/<id>", data = "<input>")]
+async fn update_post_api(id: &str, input: Json<PostInput>, user: AuthenticatedUser, db: Connection<MainDatabase>) -> ApiResult<BlogPost> {
+    let post = managed_post(&db, id, &user).await?;
+    let post = update_post(&db, post._id, input.into_inner().try_into()?, PostAuthor::from(&user)).await?;
+    post.map(Json).ok_or_else(ApiError::not_found)
+}
+
+#[delete("/posts/<id>")]
+async fn delete_post_api(id: &str, user: AuthenticatedUser, db: Connection<MainDatabase>) -> Result<Status, ApiError> {
+    let post = managed_post(&db, id, &user).await?;
+    if delete_post(&db, post._id).await? {
+        return Ok(Status::NoContent);
+    }
+    Err(ApiError::not_found())
+}
@@ -0,0 +1,211 @@
+// threaded comments on blog posts with an admin moderation queue, mounted under blog::BASE
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use rocket_csrf::CsrfToken;
+use rocket::{form::Form, Route, http::Status, response::Redirect, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::FindOptions};
+use rocket_dyn_templates::{Template, context};
+use bson::{self, doc, oid::ObjectId, Document};
+use crate::blog::{self, BlogPost, find_post};
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::users::{self, AdminUser, AuthenticatedUser};
+
+pub const MAX_COMMENT_LENGTH: usize = 5000;
+// replies nested deeper than this are shown at this depth
+pub const MAX_DISPLAY_DEPTH: usize = 5;
+
+pub fn routes() -> Vec<Route> {
+    routes![post_comment, moderation_queue, moderate_comment]
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentStatus {
+    Pending,
+    Approved,
+    Spam,
+}
+
+impl CommentStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CommentStatus::Pending => "pending",
+            CommentStatus::Approved => "approved",
+            CommentStatus::Spam => "spam",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Comment {
+    pub _id: ObjectId,
+    pub post_id: ObjectId,
+    // the comment being replied to, None for top level comments
+    pub parent_id: Option<ObjectId>,
+    pub username: String,
+    pub content: String,
+    pub created_time: bson::DateTime,
+    pub created_str: String,
+    pub status: CommentStatus,
+}
+
+// a comment in the order it is displayed along with how far it is indented
+#[derive(Debug, Serialize)]
+pub struct ThreadedComment {
+    comment: Comment,
+    depth: usize,
+}
+
+#[derive(FromForm)]
+struct CommentData<'r> {
+    authenticity_token: String,
+    content: &'r str,
+    parent_id: Option<&'r str>,
+}
+
+#[derive(FromForm)]
+struct ModerationData {
+    authenticity_token: String,
+    // approve, spam, pending or delete
+    action: String,
+}
+
+// orders comments depth first so that replies directly follow their parent
+fn thread(comments: Vec<Comment>) -> Vec<ThreadedComment> {
+    let mut children: HashMap<Option<ObjectId>, Vec<Comment>> = HashMap::new();
+    for comment in comments {
+        children.entry(comment.parent_id).or_default().push(comment);
+    }
+    let mut threaded = Vec::new();
+    // replies to comments that are not approved (or were deleted) are shown at the top level
+    let known: Vec<ObjectId> = children.values().flatten().map(|c| c._id).collect();
+    let orphans: Vec<Comment> = children.iter()
+        .filter(|(parent, _)| matches!(parent, Some(parent) if !known.contains(parent)))
+        .flat_map(|(_, comments)| comments.clone())
+        .collect();
+    let mut stack: Vec<(Comment, usize)> = Vec::new();
+    let mut roots = children.remove(&None).unwrap_or_default();
+    roots.extend(orphans);
+    roots.sort_by_key(|c| c.created_time);
+    stack.extend(roots.into_iter().rev().map(|c| (c, 0)));
+    while let Some((comment, depth)) = stack.pop() {
+        if let Some(replies) = children.remove(&Some(comment._id)) {
+            stack.extend(replies.into_iter().rev().map(|c| (c, depth + 1)));
+        }
+        threaded.push(ThreadedComment { comment, depth: depth.min(MAX_DISPLAY_DEPTH) });
+    }
+    threaded
+}
+
+// approved comments of a post in display order
+pub async fn post_comments(db: &mongodb::Client, post_id: ObjectId) -> mongodb::error::Result<Vec<ThreadedComment>> {
+    let find_options = FindOptions::builder().sort(doc! {"created_time": 1}).build();
+    let filter = doc! {"post_id": post_id, "status": CommentStatus::Approved.as_str()};
+    let comments: Vec<Comment> = db.comments_coll().find(filter, find_options).await?.try_collect().await?;
+    Ok(thread(comments))
+}
+
+// number of approved comments for each post, keyed by the hex post id (0 when there are none)
+pub async fn comment_counts(db: &mongodb::Client, posts: &[BlogPost]) -> mongodb::error::Result<HashMap<String, i64>> {
+    let ids: Vec<ObjectId> = posts.iter().map(|post| post._id).collect();
+    let mut counts: HashMap<String, i64> = posts.iter().map(|post| (post.id(), 0)).collect();
+    let pipeline = vec![
+        doc! {"$match": {"post_id": {"$in": ids}, "status": CommentStatus::Approved.as_str()}},
+        doc! {"$group": {"_id": "$post_id", "count": {"$sum": 1}}},
+    ];
+    let groups: Vec<Document> = db.comments_coll().aggregate(pipeline, None).await?.try_collect().await?;
+    for group in groups {
+        if let (Ok(id), Ok(count)) = (group.get_object_id("_id"), group.get_i32("count")) {
+            counts.insert(id.to_hex(), count.into());
+        }
+    }
+    Ok(counts)
+}
+
+// removes every comment on a post, called when the post is deleted
+pub async fn delete_post_comments(db: &mongodb::Client, post_id: ObjectId) -> mongodb::error::Result<u64> {
+    Ok(db.comments_coll().delete_many(doc! {"post_id": post_id}, None).await?.deleted_count)
+}
+
+#[post("/posts/<id>/comments", data = "<form>")]
+async fn post_comment(id: &str, form: Form<CommentData<'_>>, csrf_token: CsrfToken, user: Option<AuthenticatedUser>, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    let post_url = uri!(blog::BASE, blog::blog_post(id)).to_string();
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(Redirect::to(uri!(users::login(Some(post_url))))),
+    };
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let content = form.content.trim();
+    if content.is_empty() || content.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(Status::UnprocessableEntity);
+    }
+    let post_id = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    match find_post(&db, post_id).await.map_err(|_e| Status::InternalServerError)? {
+        Some(post) if post.is_published() => {},
+        _ => return Err(Status::NotFound),
+    }
+    let parent_id = match form.parent_id.filter(|parent_id| !parent_id.is_empty()) {
+        Some(parent_id) => {
+            let parent_id = ObjectId::parse_str(parent_id).map_err(|_e| Status::UnprocessableEntity)?;
+            // replies must stay on the same post
+            db.comments_coll().find_one(doc! {"_id": parent_id, "post_id": post_id}, None).await
+                .map_err(|_e| Status::InternalServerError)?
+                .ok_or(Status::UnprocessableEntity)?;
+            Some(parent_id)
+        },
+        None => None,
+    };
+    let created_time = bson::DateTime::now();
+    let comment = Comment {
+        _id: ObjectId::new(),
+        post_id,
+        parent_id,
+        username: user.username,
+        content: content.to_string(),
+        created_time,
+        created_str: created_time.to_chrono().format("%Y-%b-%d %H:%M").to_string(),
+        // admins do not need to wait for moderation
+        status: if user.admin { CommentStatus::Approved } else { CommentStatus::Pending },
+    };
+    db.comments_coll().insert_one(&comment, None).await.map_err(|_e| Status::InternalServerError)?;
+    // TODO: flash "your comment is awaiting moderation"
+    Ok(Redirect::to(post_url))
+}
+
+// /blog/comments/moderation?status=spam
+#[get("/comments/moderation?<status>")]
+async fn moderation_queue(status: Option<CommentStatus>, _user: AdminUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    let status = status.unwrap_or(CommentStatus::Pending);
+    let find_options = FindOptions::builder().sort(doc! {"created_time": 1}).limit(100).build();
+    let comments: Vec<Comment> = db.comments_coll().find(doc! {"status": status.as_str()}, find_options).await
+        .map_err(|_e| Status::InternalServerError)?
+        .try_collect().await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Template::render("blog/moderation", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        status: status.as_str(),
+        comments,
+    }))
+}
+
+#[post("/comments/<id>/moderate", data = "<form>")]
+async fn moderate_comment(id: &str, form: Form<ModerationData>, _user: AdminUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let oid = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    let status = match form.action.as_str() {
+        "approve" => CommentStatus::Approved,
+        "spam" => CommentStatus::Spam,
+        "pending" => CommentStatus::Pending,
+        "delete" => {
+            db.comments_coll().delete_one(doc! {"_id": oid}, None).await.map_err(|_e| Status::InternalServerError)?;
+            return Ok(Redirect::to(uri!(blog::BASE, moderation_queue(_))));
+        },
+        _ => return Err(Status::UnprocessableEntity),
+    };
+    db.comments_coll().update_one(doc! {"_id": oid}, doc! {"$set": {"status": status.as_str()}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(blog::BASE, moderation_queue(_))))
+}
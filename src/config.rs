@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::mailer::MailerKind;
+use crate::oauth::OAuthProvider;
+
+// site wide settings read from Rocket.toml, attached in main.rs with AdHoc::config
+// usage : (config: &State<SiteConfig>, ...)
+#[derive(Debug, Deserialize)]
+pub struct SiteConfig {
+    // absolute base url (scheme + host) used wherever a full link is needed e.g. feeds
+    #[serde(default = "default_site_url")]
+    pub site_url: String,
+    #[serde(default = "default_site_name")]
+    pub site_name: String,
+    // number of posts included in the RSS and Atom feeds
+    #[serde(default = "default_feed_size")]
+    pub feed_size: i64,
+    // posts per page on the blog listing and default limit for the JSON API
+    #[serde(default = "default_page_size")]
+    pub page_size: u64,
+    // set to false on deployments that cannot create text indexes to skip straight to regex search
+    #[serde(default = "default_true")]
+    pub search_text_index: bool,
+    // where uploaded images and attachments are kept
+    #[serde(default)]
+    pub upload_storage: UploadStorage,
+    // directory used when upload_storage = "disk"
+    #[serde(default = "default_upload_dir")]
+    pub upload_dir: String,
+    // keep in sync with limits.file in Rocket.toml
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: u64,
+    // decode and re-encode JPEG and PNG uploads, this strips metadata such as GPS locations
+    #[serde(default = "default_true")]
+    pub upload_reencode: bool,
+    #[serde(default = "default_true")]
+    pub upload_thumbnails: bool,
+    #[serde(default = "default_thumbnail_size")]
+    pub thumbnail_size: u32,
+    // pages that are not generated from posts but should still be listed in sitemap.xml
+    #[serde(default = "default_sitemap_pages")]
+    pub sitemap_pages: Vec<String>,
+    // path prefixes crawlers are asked to stay out of, written to robots.txt
+    #[serde(default = "default_robots_disallow")]
+    pub robots_disallow: Vec<String>,
+    // language of posts without one and of the UI when Accept-Language has no supported match, see i18n.rs
+    #[serde(default = "default_locale")]
+    pub default_locale: String,
+    // "smtp" or "file", see mailer.rs
+    #[serde(default)]
+    pub mailer: MailerKind,
+    #[serde(default = "default_mail_from")]
+    pub mail_from: String,
+    // directory used when mailer = "file"
+    #[serde(default = "default_mail_dir")]
+    pub mail_dir: String,
+    #[serde(default)]
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    // how long a sign-up verification code or link can be used for
+    #[serde(default = "default_verification_hours")]
+    pub verification_hours: i64,
+    // how long an emailed password reset link works for
+    #[serde(default = "default_password_reset_minutes")]
+    pub password_reset_minutes: i64,
+    // admins can't use admin pages until they have set up two-factor, only until an admin changes it on /account/two-factor
+    #[serde(default)]
+    pub require_admin_two_factor: bool,
+    // new passwords need this many characters and at least this zxcvbn score (0-4), see password_policy.rs
+    #[serde(default = "default_password_min_length")]
+    pub password_min_length: usize,
+    #[serde(default = "default_password_min_strength")]
+    pub password_min_strength: u8,
+    // directory of Have I Been Pwned range files, new passwords found there are turned down
+    pub breached_passwords_dir: Option<String>,
+    // Argon2id cost of new password hashes, older hashes are redone with these the next time their owner logs in
+    #[serde(default = "default_argon2_memory_kib")]
+    pub argon2_memory_kib: u32,
+    #[serde(default = "default_argon2_iterations")]
+    pub argon2_iterations: u32,
+    #[serde(default = "default_argon2_parallelism")]
+    pub argon2_parallelism: u32,
+    // social login providers keyed by the name used in their urls e.g. /auth/github, see oauth.rs
+    #[serde(default)]
+    pub oauth: BTreeMap<String, OAuthProvider>,
+}
+
+// also saved with each upload so files are still found after the setting changes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadStorage {
+    #[default]
+    Disk,
+    GridFs,
+}
+
+fn default_site_url() -> String {
+    "http://localhost:8000".to_string()
+}
+
+fn default_site_name() -> String {
+    "Blog".to_string()
+}
+
+fn default_feed_size() -> i64 {
+    20
+}
+
+fn default_page_size() -> u64 {
+    50
+}
+
+fn default_upload_dir() -> String {
+    "uploads".to_string()
+}
+
+fn default_max_upload_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_thumbnail_size() -> u32 {
+    400
+}
+
+fn default_sitemap_pages() -> Vec<String> {
+    vec!["/blog".to_string(), "/blog/search".to_string()]
+}
+
+fn default_robots_disallow() -> Vec<String> {
+    ["/api/", "/account/", "/login", "/sign-up", "/verify-email", "/forgot-password", "/reset-password/", "/auth/", "/blog/new-post", "/blog/uploads/new", "/blog/comments/"].map(String::from).to_vec()
+}
+
+pub fn default_locale() -> String {
+    "en".to_string()
+}
+
+fn default_mail_from() -> String {
+    "Blog <noreply@localhost>".to_string()
+}
+
+fn default_mail_dir() -> String {
+    "mail".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_verification_hours() -> i64 {
+    24
+}
+
+fn default_password_reset_minutes() -> i64 {
+    60
+}
+
+fn default_password_min_length() -> usize {
+    10
+}
+
+fn default_password_min_strength() -> u8 {
+    3
+}
+
+// the argon2 crate's defaults, the OWASP minimum for Argon2id
+fn default_argon2_memory_kib() -> u32 {
+    19 * 1024
+}
+
+fn default_argon2_iterations() -> u32 {
+    2
+}
+
+fn default_argon2_parallelism() -> u32 {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl SiteConfig {
+    // turns a path such as /blog/posts/<id> into an absolute url
+    pub fn absolute_url(&self, path: impl std::fmt::Display) -> String {
+        format!("{}{}", self.site_url.trim_end_matches('/'), path)
+    }
+}
@@ -5,6 +5,19 @@ use rocket_db_pools::{mongodb, Database};
 use bson::{doc, oid::ObjectId};
 use mongodb::{options::IndexOptions, IndexModel};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use crate::blog::BlogPost;
+use crate::comments::Comment;
+use crate::two_factor::{LoginChallenge, TwoFactorSettings};
+use crate::email_verification::EmailVerification;
+use crate::login_attempts::LoginFailure;
+use crate::oauth::{ExternalIdentity, OAuthAttempt};
+use crate::passkeys::{PasskeyCeremony, PasskeyCredential};
+use crate::password_reset::PasswordReset;
+use crate::revisions::Revision;
+use crate::sessions::{Session, SESSION_IDLE_DAYS};
+use crate::uploads::Upload;
+use crate::views::{ViewDay, ViewMark};
 
 pub const MAIN_DATABASE_NAME: &'static str = "app_name";
 
@@ -17,6 +30,38 @@ pub trait DatabaseUtils {
     fn app_db(&self) -> mongodb::Database;
 
     fn users_coll(&self) -> mongodb::Collection<User>;
+
+    fn posts_coll(&self) -> mongodb::Collection<BlogPost>;
+
+    fn comments_coll(&self) -> mongodb::Collection<Comment>;
+
+    fn revisions_coll(&self) -> mongodb::Collection<Revision>;
+
+    fn uploads_coll(&self) -> mongodb::Collection<Upload>;
+
+    fn view_marks_coll(&self) -> mongodb::Collection<ViewMark>;
+
+    fn view_days_coll(&self) -> mongodb::Collection<ViewDay>;
+
+    fn sessions_coll(&self) -> mongodb::Collection<Session>;
+
+    fn email_verifications_coll(&self) -> mongodb::Collection<EmailVerification>;
+
+    fn password_resets_coll(&self) -> mongodb::Collection<PasswordReset>;
+
+    fn login_challenges_coll(&self) -> mongodb::Collection<LoginChallenge>;
+
+    fn two_factor_settings_coll(&self) -> mongodb::Collection<TwoFactorSettings>;
+
+    fn passkeys_coll(&self) -> mongodb::Collection<PasskeyCredential>;
+
+    fn passkey_ceremonies_coll(&self) -> mongodb::Collection<PasskeyCeremony>;
+
+    fn external_identities_coll(&self) -> mongodb::Collection<ExternalIdentity>;
+
+    fn oauth_attempts_coll(&self) -> mongodb::Collection<OAuthAttempt>;
+
+    fn login_failures_coll(&self) -> mongodb::Collection<LoginFailure>;
 }
 
 impl DatabaseUtils for mongodb::Client {
@@ -27,24 +72,160 @@ impl DatabaseUtils for mongodb::Client {
     fn users_coll(&self) -> mongodb::Collection<User> {
         self.app_db().collection::<User>("users")
     }
+
+    fn posts_coll(&self) -> mongodb::Collection<BlogPost> {
+        self.app_db().collection::<BlogPost>("posts")
+    }
+
+    fn comments_coll(&self) -> mongodb::Collection<Comment> {
+        self.app_db().collection::<Comment>("comments")
+    }
+
+    fn revisions_coll(&self) -> mongodb::Collection<Revision> {
+        self.app_db().collection::<Revision>("revisions")
+    }
+
+    fn uploads_coll(&self) -> mongodb::Collection<Upload> {
+        self.app_db().collection::<Upload>("uploads")
+    }
+
+    fn view_marks_coll(&self) -> mongodb::Collection<ViewMark> {
+        self.app_db().collection::<ViewMark>("view_marks")
+    }
+
+    fn view_days_coll(&self) -> mongodb::Collection<ViewDay> {
+        self.app_db().collection::<ViewDay>("view_days")
+    }
+
+    fn sessions_coll(&self) -> mongodb::Collection<Session> {
+        self.app_db().collection::<Session>("sessions")
+    }
+
+    fn email_verifications_coll(&self) -> mongodb::Collection<EmailVerification> {
+        self.app_db().collection::<EmailVerification>("email_verifications")
+    }
+
+    fn password_resets_coll(&self) -> mongodb::Collection<PasswordReset> {
+        self.app_db().collection::<PasswordReset>("password_resets")
+    }
+
+    fn login_challenges_coll(&self) -> mongodb::Collection<LoginChallenge> {
+        self.app_db().collection::<LoginChallenge>("login_challenges")
+    }
+
+    fn two_factor_settings_coll(&self) -> mongodb::Collection<TwoFactorSettings> {
+        self.app_db().collection::<TwoFactorSettings>("site_settings")
+    }
+
+    fn passkeys_coll(&self) -> mongodb::Collection<PasskeyCredential> {
+        self.app_db().collection::<PasskeyCredential>("passkeys")
+    }
+
+    fn passkey_ceremonies_coll(&self) -> mongodb::Collection<PasskeyCeremony> {
+        self.app_db().collection::<PasskeyCeremony>("passkey_ceremonies")
+    }
+
+    fn external_identities_coll(&self) -> mongodb::Collection<ExternalIdentity> {
+        self.app_db().collection::<ExternalIdentity>("external_identities")
+    }
+
+    fn oauth_attempts_coll(&self) -> mongodb::Collection<OAuthAttempt> {
+        self.app_db().collection::<OAuthAttempt>("oauth_attempts")
+    }
+
+    fn login_failures_coll(&self) -> mongodb::Collection<LoginFailure> {
+        self.app_db().collection::<LoginFailure>("login_failures")
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct User {
+    _id: ObjectId,
     username: String,
     password: String,
     admin: bool,
+    // shown on posts and author pages, empty means use the username
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    bio: String,
+    // accounts created before sign-up asked for an email have none
+    #[serde(default)]
+    email: Option<String>,
+    // unverified users can't log in, older accounts count as verified
+    #[serde(default = "legacy_email_verified")]
+    email_verified: bool,
+    // base32 TOTP secret once two-factor is on, see two_factor.rs
+    #[serde(default)]
+    totp_secret: Option<String>,
+    // set while enrolling, it becomes totp_secret once a code from it is confirmed
+    #[serde(default)]
+    totp_pending_secret: Option<String>,
+    // the newest time step a code was accepted for, older codes can't be replayed
+    #[serde(default)]
+    totp_last_step: Option<i64>,
+    // sha-256 of the unused recovery codes
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+}
+
+fn legacy_email_verified() -> bool {
+    true
+}
+
+// the public parts of a user, safe to hand to templates
+#[derive(Clone, Debug, Serialize)]
+pub struct UserProfile {
+    pub username: String,
+    pub display_name: String,
+    pub bio: String,
 }
 
 impl User {
-    pub fn new<S: Into<String>>(username: S, password: S) -> User {
-        User { username: username.into(), password: password.into(), admin: false }
+    // new users have to verify their email before they can log in, see email_verification.rs
+    pub fn new<S: Into<String>>(username: S, password: S, email: S) -> User {
+        User {
+            _id: ObjectId::new(),
+            username: username.into(),
+            password: password.into(),
+            admin: false,
+            display_name: String::new(),
+            bio: String::new(),
+            email: Some(email.into()),
+            email_verified: false,
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+        }
+    }
+
+    // made on a first social login, the provider has already checked the email if there is one, see oauth.rs
+    pub fn external<S: Into<String>>(username: S, password: S, email: Option<String>) -> User {
+        let mut user = User::new(username.into(), password.into(), String::new());
+        user.email = email;
+        user.email_verified = true;
+        user
+    }
+
+    pub fn id(&self) -> ObjectId {
+        self._id
     }
 
     pub fn username(&self) -> &str {
         &self.username
     }
 
+    // None until the user picks one, profile() falls back to the username
+    pub fn display_name(&self) -> Option<&str> {
+        Some(self.display_name.trim()).filter(|display_name| !display_name.is_empty())
+    }
+
+    pub fn profile(&self) -> UserProfile {
+        let display_name = if self.display_name.trim().is_empty() { &self.username } else { &self.display_name };
+        UserProfile { username: self.username.clone(), display_name: display_name.clone(), bio: self.bio.clone() }
+    }
+
     pub fn password_hash(&self) -> &str {
         &self.password
     }
@@ -52,6 +233,26 @@ impl User {
     pub fn admin(&self) -> bool {
         self.admin
     }
+
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    pub fn email_verified(&self) -> bool {
+        self.email_verified
+    }
+
+    pub fn totp_secret(&self) -> Option<&str> {
+        self.totp_secret.as_deref()
+    }
+
+    pub fn totp_pending_secret(&self) -> Option<&str> {
+        self.totp_pending_secret.as_deref()
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_codes.len()
+    }
 }
 
 pub async fn create_indexes(rocket: Rocket<Build>) -> fairing::Result {
@@ -65,6 +266,221 @@ pub async fn create_indexes(rocket: Rocket<Build>) -> fairing::Result {
                 None,
             )
             .await.ok();
+        // one account per email address, older accounts have none
+        db.0.users_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"email": 1})
+                    .options(IndexOptions::builder().unique(true).partial_filter_expression(doc! {"email": {"$type": "string"}}).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // newest first listings (including cursor paging) and per-tag feeds
+        db.0.posts_coll()
+            .create_index(IndexModel::builder().keys(doc! {"published_time": -1, "_id": -1}).build(), None)
+            .await.ok();
+        db.0.posts_coll()
+            .create_index(IndexModel::builder().keys(doc! {"tags": 1, "published_time": -1, "_id": -1}).build(), None)
+            .await.ok();
+        // full-text search, a title match counts for more than a content match
+        db.0.posts_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"title": "text", "content": "text"})
+                    .options(IndexOptions::builder().name("posts_text".to_string()).weights(doc! {"title": 10, "content": 1}).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // import matches posts on their slug, older posts do not have one yet
+        db.0.posts_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"slug": 1})
+                    .options(IndexOptions::builder().unique(true).partial_filter_expression(doc! {"slug": {"$exists": true}}).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // translations of a post and swapping listings into the reader's language
+        db.0.posts_coll()
+            .create_index(IndexModel::builder().keys(doc! {"translation_group": 1, "lang": 1}).build(), None)
+            .await.ok();
+        // author pages
+        db.0.posts_coll()
+            .create_index(IndexModel::builder().keys(doc! {"author.id": 1, "published_time": -1, "_id": -1}).build(), None)
+            .await.ok();
+        // comments of a post in order and the moderation queue
+        db.0.comments_coll()
+            .create_index(IndexModel::builder().keys(doc! {"post_id": 1, "status": 1, "created_time": 1}).build(), None)
+            .await.ok();
+        db.0.comments_coll()
+            .create_index(IndexModel::builder().keys(doc! {"status": 1, "created_time": 1}).build(), None)
+            .await.ok();
+        // history of a post, newest first
+        db.0.revisions_coll()
+            .create_index(IndexModel::builder().keys(doc! {"post_id": 1, "created_time": -1}).build(), None)
+            .await.ok();
+        // popular posts of all time
+        db.0.posts_coll()
+            .create_index(IndexModel::builder().keys(doc! {"view_count": -1, "published_time": -1}).build(), None)
+            .await.ok();
+        // view marks are only needed for the day they were made
+        db.0.view_marks_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"created_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(2 * 24 * 60 * 60)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // popular posts and stats over a range of days
+        db.0.view_days_coll()
+            .create_index(IndexModel::builder().keys(doc! {"day": 1, "post_id": 1}).build(), None)
+            .await.ok();
+        // login sessions by token and by user, idle ones expire
+        db.0.sessions_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"token_hash": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        db.0.sessions_coll()
+            .create_index(IndexModel::builder().keys(doc! {"user_id": 1, "last_seen": -1}).build(), None)
+            .await.ok();
+        db.0.sessions_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"last_seen": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(SESSION_IDLE_DAYS as u64 * 24 * 60 * 60)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // pending sign-up codes, gone once they expire
+        db.0.email_verifications_coll()
+            .create_index(IndexModel::builder().keys(doc! {"user_id": 1}).build(), None)
+            .await.ok();
+        db.0.email_verifications_coll()
+            .create_index(IndexModel::builder().keys(doc! {"token_hash": 1}).build(), None)
+            .await.ok();
+        db.0.email_verifications_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // reset links by token, recent requests per account and per IP for rate limiting
+        db.0.password_resets_coll()
+            .create_index(IndexModel::builder().keys(doc! {"token_hash": 1}).build(), None)
+            .await.ok();
+        db.0.password_resets_coll()
+            .create_index(IndexModel::builder().keys(doc! {"user_id": 1, "created_time": -1}).build(), None)
+            .await.ok();
+        db.0.password_resets_coll()
+            .create_index(IndexModel::builder().keys(doc! {"ip": 1, "created_time": -1}).build(), None)
+            .await.ok();
+        db.0.password_resets_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // second login steps waiting for a code, gone once they expire
+        db.0.login_challenges_coll()
+            .create_index(IndexModel::builder().keys(doc! {"token_hash": 1}).build(), None)
+            .await.ok();
+        db.0.login_challenges_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // passkeys of a user, and each authenticator only once
+        db.0.passkeys_coll()
+            .create_index(IndexModel::builder().keys(doc! {"user_id": 1}).build(), None)
+            .await.ok();
+        db.0.passkeys_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"credential_id": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // passkey ceremonies waiting to be finished, gone once they expire
+        db.0.passkey_ceremonies_coll()
+            .create_index(IndexModel::builder().keys(doc! {"token_hash": 1}).build(), None)
+            .await.ok();
+        db.0.passkey_ceremonies_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // each external account belongs to one user
+        db.0.external_identities_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"provider": 1, "subject": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        db.0.external_identities_coll()
+            .create_index(IndexModel::builder().keys(doc! {"user_id": 1}).build(), None)
+            .await.ok();
+        // trips to OAuth providers, gone once they expire
+        db.0.oauth_attempts_coll()
+            .create_index(IndexModel::builder().keys(doc! {"state_hash": 1}).build(), None)
+            .await.ok();
+        db.0.oauth_attempts_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
+        // failed logins, counted per username and per address and kept for the audit log
+        db.0.login_failures_coll()
+            .create_index(IndexModel::builder().keys(doc! {"username": 1, "created_time": -1}).build(), None)
+            .await.ok();
+        db.0.login_failures_coll()
+            .create_index(IndexModel::builder().keys(doc! {"ip": 1, "created_time": -1}).build(), None)
+            .await.ok();
+        db.0.login_failures_coll()
+            .create_index(IndexModel::builder().keys(doc! {"created_time": -1}).build(), None)
+            .await.ok();
+        db.0.login_failures_coll()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_time": 1})
+                    .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                    .build(),
+                None,
+            )
+            .await.ok();
         return Ok(rocket);
     }
     Err(rocket)
@@ -0,0 +1,212 @@
+// sign-up email verification, new accounts can't log in (so can't post or comment) until the emailed
+// code is entered or the emailed link is followed
+use std::sync::Arc;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use rocket::{form::Form, Route, State, http::Status, response::Redirect};
+use rocket_db_pools::mongodb::{self, options::ReturnDocument, options::FindOneAndUpdateOptions};
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils, User};
+use crate::i18n::{Catalogs, Locale};
+use crate::mailer::{Email, Mailer};
+use crate::users::{self, find_user};
+use crate::utils::{random_token, token_hash};
+
+// wrong codes allowed before a new one has to be sent
+const MAX_CODE_ATTEMPTS: i32 = 5;
+// stops the resend button from being used to flood someone's inbox
+const RESEND_INTERVAL_SECONDS: i64 = 60;
+
+pub fn routes() -> Vec<Route> {
+    routes![verify_email_page, verify_email_new, verify_email_code, resend_verification]
+}
+
+// removed by a TTL index once expires_time passes
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmailVerification {
+    pub _id: ObjectId,
+    pub user_id: ObjectId,
+    // the address the code was sent to, verifying only counts if the user still has it
+    pub email: String,
+    // sha-256 of the six digit code and of the token in the link
+    pub code_hash: String,
+    pub token_hash: String,
+    pub attempts: i32,
+    pub created_time: bson::DateTime,
+    pub expires_time: bson::DateTime,
+}
+
+#[derive(Debug)]
+pub enum VerificationError {
+    Database(mongodb::error::Error),
+    Mail(String),
+}
+
+impl From<mongodb::error::Error> for VerificationError {
+    fn from(error: mongodb::error::Error) -> VerificationError {
+        VerificationError::Database(error)
+    }
+}
+
+// basic shape check, the emailed code is what proves the address works
+pub fn valid_email(email: &str) -> bool {
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    email.len() <= 254 && !local.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.')
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn new_code() -> String {
+    format!("{:06}", OsRng.next_u32() % 1_000_000)
+}
+
+// replaces any earlier code for the user and emails the new one along with a link
+pub async fn send_verification(db: &mongodb::Client, mailer: &dyn Mailer, config: &SiteConfig, catalogs: &Catalogs, user: &User, lang: &str) -> Result<(), VerificationError> {
+    let email = match user.email() {
+        Some(email) => email.to_string(),
+        None => return Ok(()),
+    };
+    let code = new_code();
+    let token = random_token(32);
+    let now = Utc::now();
+    db.email_verifications_coll().delete_many(doc! {"user_id": user.id()}, None).await?;
+    db.email_verifications_coll().insert_one(EmailVerification {
+        _id: ObjectId::new(),
+        user_id: user.id(),
+        email: email.clone(),
+        code_hash: token_hash(&code),
+        token_hash: token_hash(&token),
+        attempts: 0,
+        created_time: bson::DateTime::from_chrono(now),
+        expires_time: bson::DateTime::from_chrono(now + Duration::hours(config.verification_hours)),
+    }, None).await?;
+    let link = config.absolute_url(uri!(verify_email_page(_, _, Some(token.as_str()))));
+    let hours = config.verification_hours.to_string();
+    let args = [("site_name", config.site_name.as_str()), ("code", code.as_str()), ("link", link.as_str()), ("hours", hours.as_str())];
+    let message = Email {
+        to: email,
+        subject: catalogs.format(lang, "email.verify.subject", &args),
+        body: catalogs.format(lang, "email.verify.body", &args),
+    };
+    mailer.send(message).await.map_err(|e| VerificationError::Mail(e.to_string()))
+}
+
+// marks the user verified as long as their address hasn't changed since the code was sent
+async fn complete(db: &mongodb::Client, verification: &EmailVerification) -> mongodb::error::Result<bool> {
+    db.email_verifications_coll().delete_many(doc! {"user_id": verification.user_id}, None).await?;
+    let result = db.users_coll().update_one(
+        doc! {"_id": verification.user_id, "email": &verification.email},
+        doc! {"$set": {"email_verified": true}},
+        None,
+    ).await?;
+    Ok(result.matched_count == 1)
+}
+
+async fn verify_token(db: &mongodb::Client, token: &str) -> mongodb::error::Result<bool> {
+    let filter = doc! {"token_hash": token_hash(token), "expires_time": {"$gt": bson::DateTime::now()}};
+    match db.email_verifications_coll().find_one(filter, None).await? {
+        Some(verification) => complete(db, &verification).await,
+        None => Ok(false),
+    }
+}
+
+async fn verify_code(db: &mongodb::Client, user: &User, code: &str) -> mongodb::error::Result<bool> {
+    // counting the attempt first means guesses made in parallel all use one up
+    let filter = doc! {"user_id": user.id(), "expires_time": {"$gt": bson::DateTime::now()}, "attempts": {"$lt": MAX_CODE_ATTEMPTS}};
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    match db.email_verifications_coll().find_one_and_update(filter, doc! {"$inc": {"attempts": 1}}, options).await? {
+        Some(verification) if verification.code_hash == token_hash(code.trim()) => complete(db, &verification).await,
+        _ => Ok(false),
+    }
+}
+
+// /verify-email?username=<username> asks for the code, /verify-email?token=<token> is the emailed link
+#[get("/verify-email?<username>&<next>&<token>", rank = 1)]
+pub async fn verify_email_page(username: Option<&str>, next: Option<&str>, token: Option<&str>, csrf_token: CsrfToken, locale: Locale, db: Connection<MainDatabase>) -> Result<Result<Redirect, Template>, Status> {
+    let mut error = None;
+    if let Some(token) = token {
+        if verify_token(&db, token).await.map_err(|_e| Status::InternalServerError)? {
+            return Ok(Ok(Redirect::to(uri!(users::login(next)))));
+        }
+        error = Some("verify.invalid_link");
+    }
+    Ok(Err(Template::render("verify-email", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        username,
+        next_page: next,
+        error,
+        lang: locale.lang,
+    })))
+}
+
+#[get("/verify-email?<username>&<next>&<token>", rank = 2)]
+fn verify_email_new(username: Option<&str>, next: Option<&str>, token: Option<&str>) -> Redirect {
+    Redirect::to(uri!(verify_email_page(username, next, token)))
+}
+
+#[derive(FromForm)]
+struct CodeData<'r> {
+    authenticity_token: String,
+    next_page: Option<&'r str>,
+    username: &'r str,
+    email_code: &'r str,
+}
+
+#[post("/verify-email", data = "<form>")]
+async fn verify_email_code(form: Form<CodeData<'_>>, csrf_token: CsrfToken, locale: Locale, db: Connection<MainDatabase>) -> Result<Result<Redirect, Template>, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Ok(Ok(Redirect::to(uri!(verify_email_page(Some(form.username), form.next_page, _)))));
+    }
+    let user = find_user(&db, form.username).await.map_err(|_e| Status::InternalServerError)?;
+    if let Some(user) = user {
+        if user.email_verified() || verify_code(&db, &user, form.email_code).await.map_err(|_e| Status::InternalServerError)? {
+            return Ok(Ok(Redirect::to(uri!(users::login(form.next_page)))));
+        }
+    }
+    Ok(Err(Template::render("verify-email", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        username: form.username,
+        next_page: form.next_page,
+        error: "verify.invalid_code",
+        lang: locale.lang,
+    })))
+}
+
+#[derive(FromForm)]
+struct ResendData<'r> {
+    authenticity_token: String,
+    next_page: Option<&'r str>,
+    username: &'r str,
+}
+
+#[post("/verify-email/resend", data = "<form>")]
+async fn resend_verification(form: Form<ResendData<'_>>, csrf_token: CsrfToken, locale: Locale, db: Connection<MainDatabase>, mailer: &State<Box<dyn Mailer>>, config: &State<SiteConfig>, catalogs: &State<Arc<Catalogs>>) -> Result<Template, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let user = find_user(&db, form.username).await.map_err(|_e| Status::InternalServerError)?;
+    if let Some(user) = user.filter(|user| !user.email_verified()) {
+        let recent = bson::DateTime::from_chrono(Utc::now() - Duration::seconds(RESEND_INTERVAL_SECONDS));
+        let recently_sent = db.email_verifications_coll().count_documents(doc! {"user_id": user.id(), "created_time": {"$gt": recent}}, None).await
+            .map_err(|_e| Status::InternalServerError)? > 0;
+        if !recently_sent {
+            if let Err(error) = send_verification(&db, mailer.inner().as_ref(), config, catalogs, &user, &locale.lang).await {
+                println!("{error:?}");
+            }
+        }
+    }
+    // the same answer whether or not the account exists
+    Ok(Template::render("verify-email", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        username: form.username,
+        next_page: form.next_page,
+        sent: true,
+        lang: locale.lang,
+    }))
+}
@@ -0,0 +1,60 @@
+// consistent JSON error bodies for the API routes
+use rocket::{Catcher, Request};
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use serde::Serialize;
+
+/// Every API error is sent as `{"status": 404, "error": "Not Found"}` with the matching HTTP status
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    status: u16,
+    error: String,
+}
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+impl ApiError {
+    pub fn new<S: Into<String>>(status: Status, error: S) -> ApiError {
+        ApiError { status: status.code, error: error.into() }
+    }
+
+    // uses the reason phrase of the status e.g. "Not Found"
+    pub fn from_status(status: Status) -> ApiError {
+        ApiError::new(status, status.reason().unwrap_or("Unknown Error"))
+    }
+
+    pub fn not_found() -> ApiError {
+        ApiError::from_status(Status::NotFound)
+    }
+
+    pub fn internal() -> ApiError {
+        ApiError::from_status(Status::InternalServerError)
+    }
+}
+
+// database errors are logged but not leaked to clients
+impl From<rocket_db_pools::mongodb::error::Error> for ApiError {
+    fn from(error: rocket_db_pools::mongodb::error::Error) -> ApiError {
+        println!("{error:?}");
+        ApiError::internal()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        Response::build_from(Json(self).respond_to(request)?).status(status).ok()
+    }
+}
+
+// turns failing guards (401, 403), bad bodies (400, 422) and unknown routes (404) into JSON
+#[catch(default)]
+fn default_api_catcher(status: Status, _request: &Request) -> ApiError {
+    ApiError::from_status(status)
+}
+
+// register under an API base e.g. .register(blog_api::BASE, errors::api_catchers())
+pub fn api_catchers() -> Vec<Catcher> {
+    catchers![default_api_catcher]
+}
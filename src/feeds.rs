@@ -0,0 +1,138 @@
+// RSS 2.0 and Atom 1.0 feeds for the blog, mounted under blog::BASE
+use rocket::{Route, State, http::{ContentType, Status, RawStr}};
+use chrono::{DateTime, Utc};
+use crate::blog::{self, BlogPost, recent_posts};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase};
+use crate::utils::{xml_escape, ConditionalBody};
+
+pub fn routes() -> Vec<Route> {
+    routes![rss_feed, atom_feed, tag_rss_feed, tag_atom_feed]
+}
+
+// links and titles that differ between the full feed and a per-tag feed
+struct FeedInfo {
+    title: String,
+    self_url: String,
+    page_url: String,
+}
+
+impl FeedInfo {
+    fn new(config: &SiteConfig, tag: Option<&str>, file_name: &str) -> FeedInfo {
+        match tag {
+            Some(tag) => {
+                let encoded_tag = RawStr::new(tag).percent_encode();
+                FeedInfo {
+                    title: format!("{} - {}", config.site_name, tag),
+                    self_url: config.absolute_url(format!("{}/tags/{}/{}", blog::BASE, encoded_tag, file_name)),
+                    page_url: config.absolute_url(format!("{}/tags/{}", blog::BASE, encoded_tag)),
+                }
+            },
+            None => FeedInfo {
+                title: config.site_name.clone(),
+                self_url: config.absolute_url(format!("{}/{}", blog::BASE, file_name)),
+                page_url: config.absolute_url(blog::BASE),
+            },
+        }
+    }
+}
+
+fn post_url(config: &SiteConfig, post: &BlogPost) -> String {
+    config.absolute_url(uri!(blog::BASE, blog::blog_post(post.id())))
+}
+
+fn last_updated(posts: &[BlogPost]) -> DateTime<Utc> {
+    posts.iter().map(BlogPost::last_modified).max().unwrap_or_default()
+}
+
+fn rss(config: &SiteConfig, info: &FeedInfo, posts: &[BlogPost]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n", xml_escape(&info.title)));
+    xml.push_str(&format!("<link>{}</link>\n", xml_escape(&info.page_url)));
+    xml.push_str(&format!("<description>{}</description>\n", xml_escape(&info.title)));
+    xml.push_str(&format!("<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\" />\n", xml_escape(&info.self_url)));
+    xml.push_str(&format!("<lastBuildDate>{}</lastBuildDate>\n", last_updated(posts).to_rfc2822()));
+    for post in posts {
+        let url = xml_escape(&post_url(config, post));
+        xml.push_str("<item>\n");
+        xml.push_str(&format!("<title>{}</title>\n", xml_escape(&post.title)));
+        xml.push_str(&format!("<link>{url}</link>\n<guid isPermaLink=\"true\">{url}</guid>\n"));
+        xml.push_str(&format!("<pubDate>{}</pubDate>\n", post.published_time.to_rfc2822()));
+        for tag in &post.tags {
+            xml.push_str(&format!("<category>{}</category>\n", xml_escape(tag)));
+        }
+        xml.push_str(&format!("<description>{}</description>\n", xml_escape(&post.content)));
+        xml.push_str("</item>\n");
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+fn atom(config: &SiteConfig, info: &FeedInfo, posts: &[BlogPost]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("<title>{}</title>\n", xml_escape(&info.title)));
+    xml.push_str(&format!("<id>{}</id>\n", xml_escape(&info.self_url)));
+    xml.push_str(&format!("<link rel=\"self\" type=\"application/atom+xml\" href=\"{}\" />\n", xml_escape(&info.self_url)));
+    xml.push_str(&format!("<link rel=\"alternate\" type=\"text/html\" href=\"{}\" />\n", xml_escape(&info.page_url)));
+    xml.push_str(&format!("<updated>{}</updated>\n", last_updated(posts).to_rfc3339()));
+    xml.push_str(&format!("<author><name>{}</name></author>\n", xml_escape(&config.site_name)));
+    for post in posts {
+        let url = xml_escape(&post_url(config, post));
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!("<title>{}</title>\n", xml_escape(&post.title)));
+        xml.push_str(&format!("<id>{url}</id>\n<link rel=\"alternate\" type=\"text/html\" href=\"{url}\" />\n"));
+        xml.push_str(&format!("<published>{}</published>\n", post.published_time.to_rfc3339()));
+        xml.push_str(&format!("<updated>{}</updated>\n", post.last_modified().to_rfc3339()));
+        for tag in &post.tags {
+            xml.push_str(&format!("<category term=\"{}\" />\n", xml_escape(tag)));
+        }
+        xml.push_str(&format!("<content type=\"text\">{}</content>\n", xml_escape(&post.content)));
+        xml.push_str("</entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+async fn feed_response(db: &Connection<MainDatabase>, config: &SiteConfig, tag: Option<&str>, atom_format: bool) -> Result<ConditionalBody, Status> {
+    let tag = tag.map(|tag| tag.to_lowercase());
+    let posts = recent_posts(db, tag.as_deref(), config.feed_size).await.map_err(|_e| Status::InternalServerError)?;
+    // an unknown tag would otherwise produce a valid but empty feed forever
+    if tag.is_some() && posts.is_empty() {
+        return Err(Status::NotFound);
+    }
+    let last_modified = last_updated(&posts);
+    if atom_format {
+        let info = FeedInfo::new(config, tag.as_deref(), "atom.xml");
+        let content_type = ContentType::new("application", "atom+xml");
+        return Ok(ConditionalBody::new(atom(config, &info, &posts), content_type, last_modified));
+    }
+    let info = FeedInfo::new(config, tag.as_deref(), "feed.xml");
+    let content_type = ContentType::new("application", "rss+xml");
+    Ok(ConditionalBody::new(rss(config, &info, &posts), content_type, last_modified))
+}
+
+// /blog/feed.xml
+#[get("/feed.xml")]
+async fn rss_feed(db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    feed_response(&db, config, None, false).await
+}
+
+// /blog/atom.xml
+#[get("/atom.xml")]
+async fn atom_feed(db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    feed_response(&db, config, None, true).await
+}
+
+// /blog/tags/rust/feed.xml
+#[get("/tags/<tag>/feed.xml")]
+async fn tag_rss_feed(tag: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    feed_response(&db, config, Some(tag), false).await
+}
+
+// /blog/tags/rust/atom.xml
+#[get("/tags/<tag>/atom.xml")]
+async fn tag_atom_feed(tag: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    feed_response(&db, config, Some(tag), true).await
+}
@@ -0,0 +1,173 @@
+// translated UI strings and language negotiation
+// catalogs are flat JSON files in locales/ e.g. locales/fr.json = {"blog.posts": "Articles", ...}
+// templates look strings up with t(key="blog.posts", lang=lang), extra arguments fill {placeholders}
+// and a count argument picks "<key>.one" or "<key>.other" when the catalog has them, see plural_form
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use serde::Serialize;
+use rocket::{Route, figment::Figment, http::{Cookie, CookieJar}, request::{FromRequest, Outcome, Request}, response::Redirect, serde::json::serde_json};
+use rocket_dyn_templates::tera::{self, Tera, Value};
+use crate::config::{SiteConfig, default_locale};
+use crate::utils::is_local_path;
+
+pub const LOCALES_DIR: &str = "locales";
+pub const LANG_COOKIE: &str = "lang";
+
+pub fn routes() -> Vec<Route> {
+    routes![set_language]
+}
+
+pub struct Catalogs {
+    default_locale: String,
+    messages: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalogs {
+    // reads every <lang>.json in dir, default_locale is taken from Rocket.toml
+    pub fn load(dir: &Path, figment: &Figment) -> Catalogs {
+        let default_locale = figment.extract_inner::<String>("default_locale").unwrap_or_else(|_e| default_locale());
+        let mut messages = HashMap::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let lang = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(lang) if path.extension().map_or(false, |extension| extension == "json") => lang.to_lowercase(),
+                _ => continue,
+            };
+            match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string())) {
+                Ok(catalog) => { messages.insert(lang, catalog); },
+                Err(error) => println!("could not load {}: {error}", path.display()),
+            }
+        }
+        Catalogs { default_locale, messages }
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    pub fn supports(&self, lang: &str) -> bool {
+        self.messages.contains_key(lang)
+    }
+
+    // sorted so language switchers are stable
+    pub fn locales(&self) -> Vec<String> {
+        let mut locales: Vec<String> = self.messages.keys().cloned().collect();
+        locales.sort();
+        locales
+    }
+
+    // falls back to the default locale and then to the key itself so a missing string is obvious but not fatal
+    pub fn message<'a>(&'a self, lang: &str, key: &'a str) -> &'a str {
+        self.messages.get(lang).and_then(|catalog| catalog.get(key))
+            .or_else(|| self.messages.get(&self.default_locale).and_then(|catalog| catalog.get(key)))
+            .map(String::as_str)
+            .unwrap_or(key)
+    }
+
+    // a message with its {placeholders} filled in, for text built outside templates such as emails
+    pub fn format(&self, lang: &str, key: &str, args: &[(&str, &str)]) -> String {
+        let mut message = self.message(lang, key).to_string();
+        for (name, value) in args {
+            message = message.replace(&format!("{{{name}}}"), value);
+        }
+        message
+    }
+
+    // the best supported language for an Accept-Language header e.g. "fr-CA,fr;q=0.9,en;q=0.8"
+    pub fn negotiate(&self, accept_language: &str) -> Option<String> {
+        let mut wanted: Vec<(String, f32)> = accept_language.split(',').filter_map(|part| {
+            let mut pieces = part.split(';');
+            let tag = pieces.next()?.trim().to_lowercase();
+            let quality = pieces.find_map(|piece| piece.trim().strip_prefix("q=")).and_then(|q| q.parse().ok()).unwrap_or(1.0);
+            if tag.is_empty() || quality <= 0.0 {
+                return None;
+            }
+            Some((tag, quality))
+        }).collect();
+        // stable, so equal qualities keep the order the browser sent them in
+        wanted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        wanted.into_iter().find_map(|(tag, _)| {
+            let primary = tag.split('-').next().unwrap_or_default().to_string();
+            if self.supports(&tag) {
+                Some(tag)
+            } else if self.supports(&primary) {
+                Some(primary)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// "one" or "other" for count in lang, French uses the singular for 0 too ("0 commentaire")
+fn plural_form(lang: &str, count: i64) -> &'static str {
+    let singular = match lang.split('-').next().unwrap_or_default() {
+        "fr" => count == 0 || count == 1,
+        _ => count == 1,
+    };
+    if singular { "one" } else { "other" }
+}
+
+// registers t() with Tera, call from Template::custom
+pub fn register(tera: &mut Tera, catalogs: Arc<Catalogs>) {
+    tera.register_function("t", move |args: &HashMap<String, Value>| -> tera::Result<Value> {
+        let key = args.get("key").and_then(Value::as_str).ok_or_else(|| tera::Error::msg("t() needs a key"))?;
+        let lang = args.get("lang").and_then(Value::as_str).unwrap_or(catalogs.default_locale());
+        let plural_key = args.get("count").and_then(Value::as_i64).map(|count| format!("{key}.{}", plural_form(lang, count)));
+        let mut message = match &plural_key {
+            Some(plural_key) if catalogs.message(lang, plural_key) != plural_key => catalogs.message(lang, plural_key).to_string(),
+            _ => catalogs.message(lang, key).to_string(),
+        };
+        for (name, value) in args.iter().filter(|(name, _)| !matches!(name.as_str(), "key" | "lang")) {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            message = message.replace(&format!("{{{name}}}"), &value);
+        }
+        Ok(Value::String(message))
+    });
+}
+
+// the language a page is shown in: the lang cookie if set, then Accept-Language, then the default
+#[derive(Clone, Debug, Serialize)]
+pub struct Locale {
+    pub lang: String,
+    // every language with a catalog, for language switchers
+    pub locales: Vec<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Locale {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let catalogs = match request.rocket().state::<Arc<Catalogs>>() {
+            Some(catalogs) => catalogs,
+            None => {
+                let lang = request.rocket().state::<SiteConfig>().map_or_else(default_locale, |config| config.default_locale.clone());
+                return Outcome::Success(Locale { lang: lang.clone(), locales: vec![lang] });
+            },
+        };
+        let from_cookie = request.cookies().get(LANG_COOKIE).map(|cookie| cookie.value().to_lowercase()).filter(|lang| catalogs.supports(lang));
+        let lang = from_cookie
+            .or_else(|| request.headers().get_one("Accept-Language").and_then(|header| catalogs.negotiate(header)))
+            .unwrap_or_else(|| catalogs.default_locale().to_string());
+        Outcome::Success(Locale { lang, locales: catalogs.locales() })
+    }
+}
+
+// /lang/fr?next=/blog remembers the choice in a cookie that overrides Accept-Language
+#[get("/lang/<lang>?<next>")]
+fn set_language(lang: &str, next: Option<&str>, jar: &CookieJar<'_>, catalogs: &rocket::State<Arc<Catalogs>>) -> Redirect {
+    let lang = lang.to_lowercase();
+    if catalogs.supports(&lang) {
+        jar.add(Cookie::build(LANG_COOKIE, lang).path("/").permanent().finish());
+    }
+    // only local paths, never redirect off site
+    match next.filter(|next| is_local_path(next)) {
+        Some(next) => Redirect::to(next.to_string()),
+        None => Redirect::to("/blog"),
+    }
+}
@@ -0,0 +1,316 @@
+// command line import and export of blog posts, run instead of the server when the first argument is import or export
+//   cargo run -- export markdown <directory>   one <slug>.md per post with YAML front matter
+//   cargo run -- export json <file>            a single JSON archive
+//   cargo run -- import markdown <directory>
+//   cargo run -- import json <file>
+// importing matches posts on their slug so running it again only updates what changed
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use rocket::{futures::TryStreamExt, serde::json::serde_json, tokio::fs};
+use rocket_db_pools::mongodb::{self, options::FindOptions};
+use bson::doc;
+use crate::blog::{self, BlogPost, PostAuthor, PostDraft, save_edit, unique_slug};
+use crate::databases::DatabaseUtils;
+use crate::related::find_related;
+use crate::revisions::record_revision;
+use crate::users::find_user;
+use crate::utils::slugify;
+
+pub const ARCHIVE_VERSION: u32 = 1;
+
+const USAGE: &str = "usage: export <markdown|json> <path> | import <markdown|json> <path>";
+
+// a post as it appears in the front matter of a Markdown file or in the JSON archive
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PostRecord {
+    pub title: String,
+    // Markdown files without one use their file name
+    #[serde(default)]
+    pub slug: String,
+    // RFC 3339, "YYYY-MM-DD HH:MM:SS" or "YYYY-MM-DD", "date" is what our old Flask site used
+    #[serde(alias = "date")]
+    pub published: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    #[serde(default)]
+    pub tags: Tags,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    // username of the author, posts by unknown users are imported without an author
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    // only set in the JSON archive, Markdown files have the content after the front matter
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub content: String,
+}
+
+// tags are accepted as a list or as one comma separated string
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Tags {
+    List(Vec<String>),
+    Text(String),
+}
+
+impl Default for Tags {
+    fn default() -> Tags {
+        Tags::List(Vec::new())
+    }
+}
+
+impl Tags {
+    fn parse(&self) -> Vec<String> {
+        match self {
+            Tags::List(tags) => blog::parse_tags(&tags.join(",")),
+            Tags::Text(tags) => blog::parse_tags(tags),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Archive {
+    version: u32,
+    exported: String,
+    posts: Vec<PostRecord>,
+}
+
+#[derive(Debug, Default)]
+struct ImportSummary {
+    created: u64,
+    updated: u64,
+    unchanged: u64,
+    failed: u64,
+}
+
+// lowercase letters, digits and single dashes, what slugify makes, so no '/', '\' or ".."
+fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty() && slugify(slug) == slug
+}
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (command, format, path) = match args {
+        [command, format, path] => (command.as_str(), format.as_str(), Path::new(path)),
+        _ => return Err(USAGE.into()),
+    };
+    let db = connect().await?;
+    match (command, format) {
+        ("export", "markdown") => {
+            let records = export_records(&db).await?;
+            fs::create_dir_all(path).await?;
+            let mut exported = 0;
+            for record in &records {
+                // the slug becomes a file name, so nothing that could point outside the directory
+                if !valid_slug(&record.slug) {
+                    println!("skipping post with slug {:?}: not a valid file name", record.slug);
+                    continue;
+                }
+                fs::write(path.join(format!("{}.md", record.slug)), to_markdown(record)?).await?;
+                exported += 1;
+            }
+            println!("exported {exported} posts to {}", path.display());
+        },
+        ("export", "json") => {
+            let posts = export_records(&db).await?;
+            let count = posts.len();
+            let archive = Archive { version: ARCHIVE_VERSION, exported: Utc::now().to_rfc3339(), posts };
+            fs::write(path, serde_json::to_string_pretty(&archive)?).await?;
+            println!("exported {count} posts to {}", path.display());
+        },
+        ("import", "markdown") => {
+            let mut records = Vec::new();
+            let mut entries = fs::read_dir(path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file = entry.path();
+                if file.extension().map_or(true, |extension| extension != "md") {
+                    continue;
+                }
+                let stem = file.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string();
+                match from_markdown(&fs::read_to_string(&file).await?, &stem) {
+                    Ok(record) => records.push(record),
+                    Err(error) => println!("skipping {}: {error}", file.display()),
+                }
+            }
+            print_summary(import_records(&db, records).await?);
+        },
+        ("import", "json") => {
+            let archive: Archive = serde_json::from_str(&fs::read_to_string(path).await?)?;
+            if archive.version > ARCHIVE_VERSION {
+                return Err(format!("archive version {} is newer than this build supports", archive.version).into());
+            }
+            print_summary(import_records(&db, archive.posts).await?);
+        },
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+// the same database the server would use, configured in Rocket.toml
+async fn connect() -> Result<mongodb::Client, Box<dyn Error>> {
+    let url: String = rocket::Config::figment().extract_inner("databases.api_db.url")?;
+    Ok(mongodb::Client::with_uri_str(url).await?)
+}
+
+fn print_summary(summary: ImportSummary) {
+    println!("created {}, updated {}, unchanged {}, failed {}", summary.created, summary.updated, summary.unchanged, summary.failed);
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    let time = time.trim();
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(time) {
+        return Ok(parsed.with_timezone(&Utc));
+    }
+    if let Ok(parsed) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S") {
+        return Ok(parsed.and_utc());
+    }
+    match NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        Ok(parsed) => Ok(parsed.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()),
+        Err(_e) => Err(format!("unrecognised date {time:?}")),
+    }
+}
+
+// every post including scheduled ones, oldest first
+async fn export_records(db: &mongodb::Client) -> Result<Vec<PostRecord>, Box<dyn Error>> {
+    let find_options = FindOptions::builder().sort(doc! {"published_time": 1, "_id": 1}).build();
+    let posts: Vec<BlogPost> = db.posts_coll().find(None, find_options).await?.try_collect().await?;
+    let mut records = Vec::with_capacity(posts.len());
+    for post in posts {
+        let slug = match post.slug.clone() {
+            Some(slug) => slug,
+            None => {
+                // saved so that importing the export into this database matches the post
+                let slug = unique_slug(db, &post.title).await?;
+                db.posts_coll().update_one(doc! {"_id": post._id}, doc! {"$set": {"slug": &slug}}, None).await?;
+                slug
+            },
+        };
+        records.push(PostRecord {
+            title: post.title,
+            slug,
+            published: post.published_time.to_rfc3339(),
+            updated: post.updated_time.map(|time| time.to_chrono().to_rfc3339()),
+            tags: Tags::List(post.tags),
+            summary: post.summary,
+            cover_image: post.cover_image,
+            lang: post.lang,
+            author: post.author.map(|author| author.username),
+            content: post.content,
+        });
+    }
+    Ok(records)
+}
+
+fn to_markdown(record: &PostRecord) -> Result<String, Box<dyn Error>> {
+    let front_matter = PostRecord {
+        title: record.title.clone(),
+        slug: record.slug.clone(),
+        published: record.published.clone(),
+        updated: record.updated.clone(),
+        tags: Tags::List(record.tags.parse()),
+        summary: record.summary.clone(),
+        cover_image: record.cover_image.clone(),
+        lang: record.lang.clone(),
+        author: record.author.clone(),
+        content: String::new(),
+    };
+    Ok(format!("---\n{}---\n\n{}\n", serde_yaml::to_string(&front_matter)?, record.content.trim_end()))
+}
+
+fn from_markdown(text: &str, file_stem: &str) -> Result<PostRecord, Box<dyn Error>> {
+    let text = text.replace("\r\n", "\n");
+    let rest = text.strip_prefix("---\n").ok_or("missing front matter")?;
+    let (front_matter, content) = match rest.split_once("\n---\n") {
+        Some(parts) => parts,
+        None => (rest.strip_suffix("\n---").ok_or("unterminated front matter")?, ""),
+    };
+    let mut record: PostRecord = serde_yaml::from_str(front_matter)?;
+    if record.slug.is_empty() {
+        record.slug = file_stem.to_string();
+    }
+    record.content = content.trim().to_string();
+    Ok(record)
+}
+
+async fn import_records(db: &mongodb::Client, records: Vec<PostRecord>) -> Result<ImportSummary, Box<dyn Error>> {
+    let mut summary = ImportSummary::default();
+    let mut authors: HashMap<String, Option<PostAuthor>> = HashMap::new();
+    for record in records {
+        let slug = record.slug.clone();
+        match import_record(db, record, &mut authors).await {
+            Ok(Some(true)) => summary.created += 1,
+            Ok(Some(false)) => summary.updated += 1,
+            Ok(None) => summary.unchanged += 1,
+            Err(error) => {
+                println!("failed to import {slug}: {error}");
+                summary.failed += 1;
+            },
+        }
+    }
+    Ok(summary)
+}
+
+// Some(true) if the post was created, Some(false) if it was updated and None if it was already up to date
+async fn import_record(db: &mongodb::Client, record: PostRecord, authors: &mut HashMap<String, Option<PostAuthor>>) -> Result<Option<bool>, Box<dyn Error>> {
+    // slugs come from files anyone could have written and are used as file names on export
+    let slug = slugify(&record.slug);
+    if slug.is_empty() {
+        return Err("missing slug".into());
+    }
+    let published_time = parse_time(&record.published)?;
+    let updated_time = record.updated.as_deref().map(parse_time).transpose()?;
+    let author = match &record.author {
+        Some(username) => {
+            if !authors.contains_key(username) {
+                let user = find_user(db, username).await?;
+                authors.insert(username.clone(), user.map(|user| PostAuthor { id: user.id(), username: user.username().to_string() }));
+            }
+            authors[username].clone()
+        },
+        None => None,
+    };
+    let draft = PostDraft {
+        title: record.title,
+        content: record.content,
+        tags: record.tags.parse(),
+        published_time: Some(published_time),
+        summary: blog::non_empty(record.summary.as_deref()),
+        cover_image: blog::non_empty(record.cover_image.as_deref()),
+        lang: blog::non_empty(record.lang.as_deref()).map(|lang| lang.to_lowercase()),
+        translation_group: None,
+        updated_time,
+    };
+    draft.validate()?;
+
+    match db.posts_coll().find_one(doc! {"slug": &slug}, None).await? {
+        Some(existing) => {
+            let unchanged = existing.title == draft.title
+                && existing.content == draft.content
+                && existing.tags == draft.tags
+                && existing.summary == draft.summary
+                && existing.cover_image == draft.cover_image
+                && (draft.lang.is_none() || existing.lang == draft.lang)
+                && existing.published_time.timestamp_millis() == published_time.timestamp_millis()
+                // a record without an updated date leaves the post's alone
+                && updated_time.map_or(true, |updated| existing.updated_time.map(|time| time.timestamp_millis()) == Some(updated.timestamp_millis()));
+            if unchanged {
+                return Ok(None);
+            }
+            save_edit(db, existing._id, draft, author, None).await?;
+            Ok(Some(false))
+        },
+        None => {
+            let mut post = BlogPost::from_draft(draft, author);
+            post.slug = Some(slug);
+            post.related_ids = Some(find_related(db, &post).await?);
+            db.posts_coll().insert_one(&post, None).await?;
+            record_revision(db, &post, post.author.as_ref(), None).await?;
+            Ok(Some(true))
+        },
+    }
+}
@@ -0,0 +1,215 @@
+// brute-force protection for the password, two-factor and passkey logins, failed attempts are counted per username and per IP address
+// after a few failures each attempt has to wait twice as long as the last, past the threshold logins are locked for a while
+// failures are tracked by the username that was typed, whether or not it exists, so the answers don't give away who has an account
+// every failure is kept for AUDIT_DAYS and admins can see them and lift locks on /account/lockouts
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use rocket::{form::Form, Route, http::Status, response::Redirect, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::{FindOneOptions, FindOptions}};
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::sessions::SessionClient;
+use crate::users::AdminUser;
+
+// failures older than this are forgotten when deciding whether to slow down or lock a login
+const FAILURE_WINDOW_MINUTES: i64 = 15;
+// failures allowed before each attempt has to wait, the wait doubles from 1 second up to MAX_DELAY_SECONDS
+const FREE_ATTEMPTS_PER_USERNAME: u64 = 3;
+const FREE_ATTEMPTS_PER_IP: u64 = 10;
+const MAX_DELAY_SECONDS: i64 = 60;
+// failures in the window that lock logins until LOCKOUT_MINUTES after the last one
+// an IP address gets more since a whole office can share one
+const LOCKOUT_PER_USERNAME: u64 = 10;
+const LOCKOUT_PER_IP: u64 = 50;
+const LOCKOUT_MINUTES: i64 = 15;
+// how long failures are kept for the audit log, removed by a TTL index on expires_time
+const AUDIT_DAYS: i64 = 90;
+const AUDIT_PAGE_SIZE: i64 = 100;
+
+pub fn routes() -> Vec<Route> {
+    routes![lockouts_page, unlock]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    // wrong password or no such user, told apart only here in the audit log
+    WrongPassword,
+    UnknownUser,
+    // a passkey login whose passkey didn't verify
+    PasskeyFailed,
+    // the password was right but the two-factor or recovery code wasn't
+    WrongCode,
+    // turned away without checking the password, these don't count towards a lockout
+    Throttled,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LoginFailure {
+    pub _id: ObjectId,
+    // normalized like stored usernames so Admin and admin share one count, kept even when no such account exists
+    pub username: String,
+    pub user_id: Option<ObjectId>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub reason: FailureReason,
+    pub created_time: bson::DateTime,
+    // set by a successful login or an admin unlock, the entry stays for the audit log but stops counting
+    pub cleared_time: Option<bson::DateTime>,
+    pub expires_time: bson::DateTime,
+}
+
+// how long to wait after the latest of `failures` failures, None when there is no need to
+fn wait_after(failures: u64, free_attempts: u64, lockout: u64) -> Option<Duration> {
+    if failures >= lockout {
+        return Some(Duration::minutes(LOCKOUT_MINUTES));
+    }
+    if failures < free_attempts {
+        return None;
+    }
+    let doublings = (failures - free_attempts).min(16) as u32;
+    Some(Duration::seconds(2i64.pow(doublings).min(MAX_DELAY_SECONDS)))
+}
+
+async fn blocked_by(db: &mongodb::Client, filter: bson::Document, free_attempts: u64, lockout: u64) -> mongodb::error::Result<Option<chrono::DateTime<Utc>>> {
+    let failures = db.login_failures_coll().count_documents(filter.clone(), None).await?;
+    let wait = match wait_after(failures, free_attempts, lockout) {
+        Some(wait) => wait,
+        None => return Ok(None),
+    };
+    let latest = db.login_failures_coll().find_one(filter, FindOneOptions::builder().sort(doc! {"created_time": -1}).build()).await?;
+    Ok(latest.map(|latest| latest.created_time.to_chrono() + wait).filter(|until| *until > Utc::now()))
+}
+
+fn recent_failures(key: &str, value: &str) -> bson::Document {
+    let since = bson::DateTime::from_chrono(Utc::now() - Duration::minutes(FAILURE_WINDOW_MINUTES));
+    doc! {key: value, "reason": {"$ne": "throttled"}, "cleared_time": null, "created_time": {"$gt": since}}
+}
+
+async fn username_blocked_until(db: &mongodb::Client, username: &str) -> mongodb::error::Result<Option<chrono::DateTime<Utc>>> {
+    blocked_by(db, recent_failures("username", username), FREE_ATTEMPTS_PER_USERNAME, LOCKOUT_PER_USERNAME).await
+}
+
+async fn ip_blocked_until(db: &mongodb::Client, ip: &str) -> mongodb::error::Result<Option<chrono::DateTime<Utc>>> {
+    blocked_by(db, recent_failures("ip", ip), FREE_ATTEMPTS_PER_IP, LOCKOUT_PER_IP).await
+}
+
+// when logins for this username or from this address can be tried again, None if they can be tried now
+pub async fn blocked_until(db: &mongodb::Client, username: &str, ip: Option<&str>) -> mongodb::error::Result<Option<chrono::DateTime<Utc>>> {
+    let for_username = username_blocked_until(db, username).await?;
+    let for_ip = match ip {
+        Some(ip) => ip_blocked_until(db, ip).await?,
+        None => None,
+    };
+    Ok(for_username.max(for_ip))
+}
+
+pub async fn record_failure(db: &mongodb::Client, username: &str, user_id: Option<ObjectId>, client: &SessionClient, reason: FailureReason) -> mongodb::error::Result<()> {
+    let now = Utc::now();
+    db.login_failures_coll().insert_one(LoginFailure {
+        _id: ObjectId::new(),
+        username: username.to_string(),
+        user_id,
+        ip: client.ip.clone(),
+        user_agent: client.user_agent.clone(),
+        reason,
+        created_time: bson::DateTime::from_chrono(now),
+        cleared_time: None,
+        expires_time: bson::DateTime::from_chrono(now + Duration::days(AUDIT_DAYS)),
+    }, None).await?;
+    Ok(())
+}
+
+// the login went all the way through, second factor included, earlier failures for the username no longer count
+// the ones from the address still do, an attacker with their own account could otherwise reset them
+pub async fn clear_failures(db: &mongodb::Client, username: &str) -> mongodb::error::Result<()> {
+    db.login_failures_coll().update_many(doc! {"username": username, "cleared_time": null}, doc! {"$set": {"cleared_time": bson::DateTime::now()}}, None).await?;
+    Ok(())
+}
+
+// what the lockouts page shows about each failure
+#[derive(Serialize)]
+struct FailureInfo {
+    username: String,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    reason: FailureReason,
+    time: String,
+    cleared: bool,
+}
+
+// a username or address that is being slowed down or locked right now
+#[derive(Serialize)]
+struct Blocked {
+    username: Option<String>,
+    ip: Option<String>,
+    until: String,
+}
+
+// /account/lockouts, who is locked out and the latest failed logins
+#[get("/account/lockouts")]
+async fn lockouts_page(_user: AdminUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    let options = FindOptions::builder().sort(doc! {"created_time": -1}).limit(AUDIT_PAGE_SIZE).build();
+    let failures: Vec<LoginFailure> = db.login_failures_coll().find(None, options).await
+        .map_err(|_e| Status::InternalServerError)?
+        .try_collect().await.map_err(|_e| Status::InternalServerError)?;
+    // only the recent ones can still be blocking anything
+    let since = Utc::now() - Duration::minutes(FAILURE_WINDOW_MINUTES);
+    let mut blocked = Vec::new();
+    let mut checked_usernames = Vec::new();
+    let mut checked_ips = Vec::new();
+    for failure in failures.iter().filter(|failure| failure.cleared_time.is_none() && failure.created_time.to_chrono() > since) {
+        if !checked_usernames.contains(&failure.username) {
+            checked_usernames.push(failure.username.clone());
+            if let Some(until) = username_blocked_until(&db, &failure.username).await.map_err(|_e| Status::InternalServerError)? {
+                blocked.push(Blocked { username: Some(failure.username.clone()), ip: None, until: until.format("%H:%M:%S UTC").to_string() });
+            }
+        }
+        if let Some(ip) = &failure.ip {
+            if !checked_ips.contains(ip) {
+                checked_ips.push(ip.clone());
+                if let Some(until) = ip_blocked_until(&db, ip).await.map_err(|_e| Status::InternalServerError)? {
+                    blocked.push(Blocked { username: None, ip: Some(ip.clone()), until: until.format("%H:%M:%S UTC").to_string() });
+                }
+            }
+        }
+    }
+    let failures: Vec<FailureInfo> = failures.into_iter().map(|failure| FailureInfo {
+        username: failure.username,
+        ip: failure.ip,
+        user_agent: failure.user_agent,
+        reason: failure.reason,
+        time: failure.created_time.to_chrono().format("%Y-%b-%d %H:%M:%S UTC").to_string(),
+        cleared: failure.cleared_time.is_some(),
+    }).collect();
+    Ok(Template::render("lockouts", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        blocked,
+        failures,
+    }))
+}
+
+#[derive(FromForm)]
+struct UnlockData {
+    authenticity_token: String,
+    username: Option<String>,
+    ip: Option<String>,
+}
+
+// lifts the lock on a username or an address, their failures stay in the audit log
+#[post("/account/lockouts/unlock", data = "<form>")]
+async fn unlock(form: Form<UnlockData>, csrf_token: CsrfToken, _user: AdminUser, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let filter = match (&form.username, &form.ip) {
+        (Some(username), None) => doc! {"username": username, "cleared_time": null},
+        (None, Some(ip)) => doc! {"ip": ip, "cleared_time": null},
+        _ => return Err(Status::BadRequest),
+    };
+    db.login_failures_coll().update_many(filter, doc! {"$set": {"cleared_time": bson::DateTime::now()}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(lockouts_page)))
+}
@@ -0,0 +1,131 @@
+// outgoing email, the implementation is picked by `mailer` in Rocket.toml
+// "smtp" sends through smtp_host, "file" writes each message to mail_dir and logs it, for development and tests
+// usage : (mailer: &State<Box<dyn Mailer>>, ...)
+use std::path::PathBuf;
+use rocket::{fairing, Build, Rocket};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use serde::Deserialize;
+use crate::config::SiteConfig;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    // plain text
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Address(String),
+    Build(String),
+    Send(String),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::Address(error) => write!(f, "invalid address: {error}"),
+            MailError::Build(error) => write!(f, "could not build message: {error}"),
+            MailError::Send(error) => write!(f, "could not send message: {error}"),
+        }
+    }
+}
+
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailerKind {
+    Smtp,
+    #[default]
+    File,
+}
+
+fn message(from: &str, email: Email) -> Result<Message, MailError> {
+    let from: Mailbox = from.parse().map_err(|e: lettre::address::AddressError| MailError::Address(e.to_string()))?;
+    let to: Mailbox = email.to.parse().map_err(|e: lettre::address::AddressError| MailError::Address(e.to_string()))?;
+    Message::builder()
+        .from(from)
+        .to(to)
+        .subject(email.subject)
+        .body(email.body)
+        .map_err(|e| MailError::Build(e.to_string()))
+}
+
+pub struct SmtpMailer {
+    from: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    // STARTTLS on smtp_port, credentials are optional for relays that trust the server
+    pub fn new(config: &SiteConfig) -> Result<SmtpMailer, MailError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+            .map_err(|e| MailError::Send(e.to_string()))?
+            .port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(SmtpMailer { from: config.mail_from.clone(), transport: builder.build() })
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let message = message(&self.from, email)?;
+        self.transport.send(message).await.map_err(|e| MailError::Send(e.to_string()))?;
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    from: String,
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(config: &SiteConfig) -> FileMailer {
+        FileMailer { from: config.mail_from.clone(), dir: PathBuf::from(&config.mail_dir) }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let to = email.to.clone();
+        let subject = email.subject.clone();
+        let message = message(&self.from, email)?;
+        tokio::fs::create_dir_all(&self.dir).await.map_err(|e| MailError::Send(e.to_string()))?;
+        // sortable by time, the id keeps messages sent in the same millisecond apart
+        let file_name = format!("{}-{}.eml", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"), bson::oid::ObjectId::new().to_hex());
+        let path = self.dir.join(file_name);
+        tokio::fs::write(&path, message.formatted()).await.map_err(|e| MailError::Send(e.to_string()))?;
+        println!("mail to {to} \"{subject}\" written to {}", path.display());
+        Ok(())
+    }
+}
+
+// attached in main.rs after the SiteConfig fairing
+pub async fn init(rocket: Rocket<Build>) -> fairing::Result {
+    let config = match rocket.state::<SiteConfig>() {
+        Some(config) => config,
+        None => return Err(rocket),
+    };
+    let mailer: Box<dyn Mailer> = match config.mailer {
+        MailerKind::Smtp => match SmtpMailer::new(config) {
+            Ok(mailer) => Box::new(mailer),
+            Err(error) => {
+                println!("{error}");
+                return Err(rocket);
+            },
+        },
+        MailerKind::File => Box::new(FileMailer::new(config)),
+    };
+    Ok(rocket.manage(mailer))
+}
@@ -1,4 +1,5 @@
 use rocket::{
+    Build, Rocket,
     response::Redirect,
     fs::{NamedFile, FileServer, relative},
     fairing::AdHoc
@@ -7,6 +8,7 @@ use reqwest;
 use reqwest::Client;
 use rocket_dyn_templates::Template;
 use std::path::Path;
+use std::sync::Arc;
 // local imports
 mod serde_examples;
 mod tauri_releases;
@@ -17,6 +19,33 @@ mod databases;
 use databases::{MainDatabase, create_indexes};
 use rocket_db_pools::Database;
 mod blog;
+mod feeds;
+mod search;
+mod blog_api;
+mod errors;
+mod comments;
+mod revisions;
+mod uploads;
+mod sitemap;
+mod seo;
+mod import_export;
+mod markdown;
+mod related;
+mod views;
+mod i18n;
+mod sessions;
+mod mailer;
+mod email_verification;
+mod password_reset;
+mod two_factor;
+mod passkeys;
+mod oauth;
+mod login_attempts;
+mod password_policy;
+mod usernames;
+use i18n::Catalogs;
+mod config;
+use config::SiteConfig;
 
 #[macro_use]
 extern crate rocket;
@@ -36,22 +65,60 @@ async fn favicon() -> Option<NamedFile> {
     NamedFile::open(Path::new("static/favicon.ico")).await.ok()
 }
 
-#[launch]
-fn rocket() -> _ {
+#[rocket::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // `cargo run -- export ...` and `cargo run -- import ...`, see import_export.rs, anything else starts the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if matches!(args.first().map(String::as_str), Some("import" | "export")) {
+        return import_export::run(&args).await;
+    }
+    let _rocket = rocket().launch().await?;
+    Ok(())
+}
+
+fn rocket() -> Rocket<Build> {
     let google_keep_release: GoogleKeepDesktopRelease = new_tauri_gh_release();
     let reqwest_client = Client::builder().user_agent("reqwest").build().expect("reqwest client could not be built");
-    rocket::build()
+    let rocket = rocket::build();
+    // UI string catalogs, shared by the t() template function and the Locale request guard
+    let catalogs = Arc::new(Catalogs::load(Path::new(i18n::LOCALES_DIR), rocket.figment()));
+    let template_catalogs = catalogs.clone();
+    rocket
         .manage(reqwest_client)
         .manage(google_keep_release)
+        .manage(catalogs)
         .attach(rocket_csrf::Fairing::default())
-        .attach(Template::fairing())
+        .attach(Template::custom(move |engines| i18n::register(&mut engines.tera, template_catalogs.clone())))
+        .attach(AdHoc::config::<SiteConfig>())
+        .attach(AdHoc::try_on_ignite("Mailer", mailer::init))
+        .attach(AdHoc::try_on_ignite("Passkeys", passkeys::init))
         // attach databases
         .attach(MainDatabase::init())
         .attach(AdHoc::try_on_ignite("Create collection indexes", create_indexes))
         .mount("/static", FileServer::from(relative!("/static")))
         .mount("/", routes![index, favicon])
         .mount("/", users::routes())
+        .mount("/", sitemap::routes())
+        .mount("/", i18n::routes())
+        .mount("/", sessions::routes())
+        .mount("/", email_verification::routes())
+        .mount("/", password_reset::routes())
+        .mount("/", two_factor::routes())
+        .mount("/", passkeys::routes())
+        .mount("/", oauth::routes())
+        .mount("/", login_attempts::routes())
         .mount(tauri_releases::BASE, tauri_releases::routes())
         .mount(serde_examples::BASE, serde_examples::routes())
         .mount(blog::BASE, blog::routes())
+        .mount(blog::BASE, feeds::routes())
+        .mount(blog::BASE, search::routes())
+        .mount(blog::BASE, comments::routes())
+        .mount(blog::BASE, revisions::routes())
+        .mount(blog::BASE, uploads::routes())
+        .mount(blog::BASE, views::routes())
+        .mount(blog_api::BASE, blog_api::routes())
+        .mount(passkeys::BASE, passkeys::api_routes())
+        .register("/", users::catchers())
+        .register(blog_api::BASE, errors::api_catchers())
+        .register(passkeys::BASE, errors::api_catchers())
 }
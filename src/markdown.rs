@@ -0,0 +1,108 @@
+// Markdown rendering of post content plus the stats and table of contents derived from it
+use serde::{Deserialize, Serialize};
+use pulldown_cmark::{html, CowStr, Event, HeadingLevel, Options, Parser, Tag};
+use crate::utils::slugify;
+
+pub const WORDS_PER_MINUTE: u64 = 200;
+
+// one heading of a post, anchor is the id given to the heading when rendered
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TocEntry {
+    pub level: u8,
+    pub text: String,
+    pub anchor: String,
+}
+
+fn options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_FOOTNOTES
+}
+
+fn level_number(level: HeadingLevel) -> u8 {
+    match level {
+        HeadingLevel::H1 => 1,
+        HeadingLevel::H2 => 2,
+        HeadingLevel::H3 => 3,
+        HeadingLevel::H4 => 4,
+        HeadingLevel::H5 => 5,
+        HeadingLevel::H6 => 6,
+    }
+}
+
+// words in the text of the post, ignoring Markdown syntax
+pub fn word_count(content: &str) -> u64 {
+    Parser::new_ext(content, options())
+        .map(|event| match event {
+            Event::Text(text) | Event::Code(text) => text.split_whitespace().count() as u64,
+            _ => 0,
+        })
+        .sum()
+}
+
+// rounded up, a post with any words takes at least a minute
+pub fn reading_minutes(words: u64) -> u64 {
+    words.div_ceil(WORDS_PER_MINUTE)
+}
+
+// headings in document order, repeated headings get -2, -3, ... appended to their anchor
+pub fn table_of_contents(content: &str) -> Vec<TocEntry> {
+    let mut toc: Vec<TocEntry> = Vec::new();
+    let mut current: Option<(u8, String)> = None;
+    for event in Parser::new_ext(content, options()) {
+        match event {
+            Event::Start(Tag::Heading(level, _, _)) => current = Some((level_number(level), String::new())),
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, heading)) = current.as_mut() {
+                    heading.push_str(&text);
+                }
+            },
+            Event::End(Tag::Heading(..)) => {
+                if let Some((level, text)) = current.take() {
+                    let base = match slugify(&text) {
+                        anchor if anchor.is_empty() => "section".to_string(),
+                        anchor => anchor,
+                    };
+                    let mut anchor = base.clone();
+                    let mut n = 1;
+                    while toc.iter().any(|entry| entry.anchor == anchor) {
+                        n += 1;
+                        anchor = format!("{base}-{n}");
+                    }
+                    toc.push(TocEntry { level, text: text.trim().to_string(), anchor });
+                }
+            },
+            _ => {},
+        }
+    }
+    toc
+}
+
+// links such as javascript:... are dropped, relative and http(s)/mailto links are kept
+fn safe_url(url: CowStr) -> CowStr {
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme.to_ascii_lowercase());
+    match scheme.as_deref() {
+        None | Some("http") | Some("https") | Some("mailto") => url,
+        // a colon after a slash, ? or # is part of a relative url not a scheme
+        Some(scheme) if scheme.contains(['/', '?', '#']) => url,
+        Some(_) => CowStr::Borrowed("#"),
+    }
+}
+
+// HTML for post content, raw HTML in the Markdown is escaped and headings get the anchors from table_of_contents
+pub fn render(content: &str) -> String {
+    let toc = table_of_contents(content);
+    let mut anchors = toc.iter().map(|entry| entry.anchor.as_str());
+    let events = Parser::new_ext(content, options()).map(|event| match event {
+        Event::Html(raw) => Event::Text(raw),
+        Event::Start(Tag::Link(kind, url, title)) => Event::Start(Tag::Link(kind, safe_url(url), title)),
+        Event::Start(Tag::Image(kind, url, title)) => Event::Start(Tag::Image(kind, safe_url(url), title)),
+        Event::Start(Tag::Heading(level, _, _)) => {
+            let anchor = anchors.next().unwrap_or_default();
+            Event::Html(CowStr::from(format!("<h{} id=\"{anchor}\">", level_number(level))))
+        },
+        Event::End(Tag::Heading(level, _, _)) => Event::Html(CowStr::from(format!("</h{}>\n", level_number(level)))),
+        event => event,
+    });
+    let mut rendered = String::with_capacity(content.len() * 3 / 2);
+    html::push_html(&mut rendered, events);
+    rendered
+}
@@ -0,0 +1,649 @@
+// "Sign in with GitHub / Google / any OpenID Connect provider" using the authorization code flow with PKCE
+// providers are configured under [default.oauth.<key>] in Rocket.toml, an external identity is linked to one User,
+// either when someone first signs in with it (a new account is made) or from /account/connections
+//
+// to try it locally run a mock provider e.g. `docker run -p 8080:8080 ghcr.io/navikt/mock-oauth2-server`
+// and add [debug.oauth.mock] with kind = "oidc", issuer = "http://localhost:8080/default"
+use std::collections::BTreeMap;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use reqwest::Client;
+use data_encoding::BASE64URL_NOPAD;
+use sha2::{Digest, Sha256};
+use rocket::{form::Form, Route, State, http::{Cookie, CookieJar, RawStr, SameSite, Status}, response::Redirect, futures::TryStreamExt, serde::json::serde_json};
+use rocket_db_pools::mongodb;
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils, User};
+use crate::i18n::Locale;
+use crate::sessions::{SessionClient, start_session};
+use crate::two_factor::{login_two_factor, start_challenge};
+use crate::users::{AuthenticatedUser, after_login_url, find_user, hash_password};
+use crate::usernames::{MAX_USERNAME_LENGTH, allowed_character, normalize_username, username_problem};
+use crate::utils::{is_duplicate_key, random_token, token_hash};
+
+pub const STATE_COOKIE: &str = "oauth_state";
+// the provider has to send the browser back within this long
+const ATTEMPT_MINUTES: i64 = 10;
+// tries at a free username when a new account's name is taken at the last moment
+const CREATE_USER_ATTEMPTS: usize = 3;
+
+pub fn routes() -> Vec<Route> {
+    routes![oauth_start, oauth_link, oauth_callback, connections_page, unlink_identity]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthKind {
+    // plain OAuth2 plus the GitHub REST API, GitHub doesn't do OpenID Connect for users
+    Github,
+    // endpoints come from <issuer>/.well-known/openid-configuration e.g. Google is issuer = "https://accounts.google.com"
+    Oidc,
+}
+
+// [default.oauth.<key>] in Rocket.toml, <key> is used in the /auth/<key> urls
+#[derive(Debug, Clone, Deserialize)]
+pub struct OAuthProvider {
+    pub kind: OAuthKind,
+    // shown on the buttons e.g. "GitHub"
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // defaults to what is needed for a username and a verified email
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+}
+
+// an external account linked to a user
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExternalIdentity {
+    pub _id: ObjectId,
+    // the key from Rocket.toml
+    pub provider: String,
+    // the provider's id for the account, never changes unlike usernames and emails
+    pub subject: String,
+    pub user_id: ObjectId,
+    pub email: Option<String>,
+    pub created_time: bson::DateTime,
+}
+
+// one trip to the provider and back, removed by a TTL index
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OAuthAttempt {
+    pub _id: ObjectId,
+    // sha-256 of the state parameter, also kept in the oauth_state cookie so only the browser that started can finish
+    pub state_hash: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub next_page: Option<String>,
+    // set when a logged in user is linking another account rather than signing in
+    pub link_user_id: Option<ObjectId>,
+    pub expires_time: bson::DateTime,
+}
+
+struct Endpoints {
+    authorization: String,
+    token: String,
+    userinfo: Option<String>,
+    // the iss every ID token has to carry, OIDC only
+    issuer: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+// who the provider says signed in
+struct ExternalProfile {
+    subject: String,
+    // only addresses the provider has verified
+    email: Option<String>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: serde_json::Value,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    nickname: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: i64,
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+impl OAuthProvider {
+    fn scopes(&self) -> String {
+        match (&self.scopes, self.kind) {
+            (Some(scopes), _) => scopes.join(" "),
+            (None, OAuthKind::Github) => "read:user user:email".to_string(),
+            (None, OAuthKind::Oidc) => "openid email profile".to_string(),
+        }
+    }
+
+    async fn endpoints(&self, client: &Client) -> Result<Endpoints, String> {
+        match self.kind {
+            OAuthKind::Github => Ok(Endpoints {
+                authorization: "https://github.com/login/oauth/authorize".to_string(),
+                token: "https://github.com/login/oauth/access_token".to_string(),
+                userinfo: None,
+                issuer: None,
+            }),
+            OAuthKind::Oidc => {
+                let issuer = self.issuer.as_deref().ok_or("oidc providers need an issuer")?.trim_end_matches('/');
+                let discovery: Discovery = client.get(format!("{issuer}/.well-known/openid-configuration")).send().await
+                    .and_then(|response| response.error_for_status()).map_err(|e| e.to_string())?
+                    .json().await.map_err(|e| e.to_string())?;
+                Ok(Endpoints {
+                    authorization: discovery.authorization_endpoint,
+                    token: discovery.token_endpoint,
+                    userinfo: discovery.userinfo_endpoint,
+                    issuer: Some(discovery.issuer),
+                })
+            },
+        }
+    }
+}
+
+fn find_provider<'a>(config: &'a SiteConfig, key: &str) -> Result<&'a OAuthProvider, Status> {
+    config.oauth.get(key).ok_or(Status::NotFound)
+}
+
+// (key, name) of every configured provider, for sign in buttons
+pub fn provider_names(config: &SiteConfig) -> Vec<(String, String)> {
+    config.oauth.iter().map(|(key, provider)| (key.clone(), provider.name.clone())).collect()
+}
+
+fn redirect_uri(config: &SiteConfig, key: &str) -> String {
+    config.absolute_url(uri!(oauth_callback(key, _, _, _)))
+}
+
+fn encode(value: &str) -> String {
+    RawStr::new(value).percent_encode().to_string()
+}
+
+// S256 PKCE challenge, RFC 7636 section 4.2
+fn code_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()))
+}
+
+async fn begin(db: &mongodb::Client, client: &Client, jar: &CookieJar<'_>, config: &SiteConfig, key: &str, next_page: Option<&str>, link_user_id: Option<ObjectId>) -> Result<Redirect, Status> {
+    let provider = find_provider(config, key)?;
+    let endpoints = provider.endpoints(client).await.map_err(|error| {
+        println!("{key}: {error}");
+        Status::BadGateway
+    })?;
+    let state = random_token(16);
+    let attempt = OAuthAttempt {
+        _id: ObjectId::new(),
+        state_hash: token_hash(&state),
+        provider: key.to_string(),
+        code_verifier: random_token(32),
+        nonce: random_token(16),
+        // checked by after_login_url when the login finishes
+        next_page: next_page.map(String::from),
+        link_user_id,
+        expires_time: bson::DateTime::from_chrono(Utc::now() + Duration::minutes(ATTEMPT_MINUTES)),
+    };
+    db.oauth_attempts_coll().insert_one(&attempt, None).await.map_err(|_e| Status::InternalServerError)?;
+    // lax, the provider sending the browser back is a cross-site navigation
+    let mut cookie = Cookie::new(STATE_COOKIE, state.clone());
+    cookie.set_same_site(SameSite::Lax);
+    jar.add_private(cookie);
+    let separator = if endpoints.authorization.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{}{separator}response_type=code&client_id={}&redirect_uri={}&scope={}&state={state}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        endpoints.authorization,
+        encode(&provider.client_id),
+        encode(&redirect_uri(config, key)),
+        encode(&provider.scopes()),
+        attempt.nonce,
+        code_challenge(&attempt.code_verifier),
+    );
+    Ok(Redirect::to(url))
+}
+
+// /auth/github?next=/blog sends the browser to the provider
+#[get("/auth/<provider>?<next>")]
+async fn oauth_start(provider: &str, next: Option<&str>, jar: &CookieJar<'_>, db: Connection<MainDatabase>, client: &State<Client>, config: &State<SiteConfig>) -> Result<Redirect, Status> {
+    begin(&db, client, jar, config, provider, next, None).await
+}
+
+// /auth/github/link adds the account to the logged in user
+#[get("/auth/<provider>/link")]
+async fn oauth_link(provider: &str, user: AuthenticatedUser, jar: &CookieJar<'_>, db: Connection<MainDatabase>, client: &State<Client>, config: &State<SiteConfig>) -> Result<Redirect, Status> {
+    begin(&db, client, jar, config, provider, Some("/account/connections"), Some(user.id)).await
+}
+
+async fn exchange_code(client: &Client, provider: &OAuthProvider, endpoints: &Endpoints, config: &SiteConfig, key: &str, code: &str, code_verifier: &str) -> Result<TokenResponse, String> {
+    let redirect_uri = redirect_uri(config, key);
+    let params = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", provider.client_id.as_str()),
+        ("client_secret", provider.client_secret.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+    client.post(&endpoints.token).header("Accept", "application/json").form(&params).send().await
+        .and_then(|response| response.error_for_status()).map_err(|e| e.to_string())?
+        .json().await.map_err(|e| e.to_string())
+}
+
+// the ID token came straight from the token endpoint over TLS, so as OpenID Connect Core 3.1.3.7 allows,
+// its signature isn't checked but every claim that ties it to this login is
+fn id_token_claims(id_token: &str, provider: &OAuthProvider, endpoints: &Endpoints, nonce: &str) -> Result<IdTokenClaims, String> {
+    let payload = id_token.split('.').nth(1).ok_or("malformed id token")?;
+    let payload = BASE64URL_NOPAD.decode(payload.trim_end_matches('=').as_bytes()).map_err(|e| e.to_string())?;
+    let claims: IdTokenClaims = serde_json::from_slice(&payload).map_err(|e| e.to_string())?;
+    if Some(claims.iss.as_str()) != endpoints.issuer.as_deref() {
+        return Err("id token issuer does not match".to_string());
+    }
+    let audience_ok = match &claims.aud {
+        serde_json::Value::String(audience) => *audience == provider.client_id,
+        serde_json::Value::Array(audiences) => audiences.iter().any(|audience| audience.as_str() == Some(provider.client_id.as_str())),
+        _ => false,
+    };
+    if !audience_ok {
+        return Err("id token is for another client".to_string());
+    }
+    if claims.exp < Utc::now().timestamp() {
+        return Err("id token has expired".to_string());
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err("id token nonce does not match".to_string());
+    }
+    Ok(claims)
+}
+
+async fn fetch_profile(client: &Client, provider: &OAuthProvider, endpoints: &Endpoints, tokens: &TokenResponse, nonce: &str) -> Result<ExternalProfile, String> {
+    match provider.kind {
+        OAuthKind::Github => {
+            let user: GithubUser = client.get("https://api.github.com/user").bearer_auth(&tokens.access_token).send().await
+                .and_then(|response| response.error_for_status()).map_err(|e| e.to_string())?
+                .json().await.map_err(|e| e.to_string())?;
+            let emails: Vec<GithubEmail> = client.get("https://api.github.com/user/emails").bearer_auth(&tokens.access_token).send().await
+                .and_then(|response| response.error_for_status()).map_err(|e| e.to_string())?
+                .json().await.map_err(|e| e.to_string())?;
+            let email = emails.into_iter().find(|email| email.primary && email.verified).map(|email| email.email);
+            Ok(ExternalProfile { subject: user.id.to_string(), email, username: Some(user.login) })
+        },
+        OAuthKind::Oidc => {
+            let id_token = tokens.id_token.as_deref().ok_or("no id token, is the openid scope missing?")?;
+            let claims = id_token_claims(id_token, provider, endpoints, nonce)?;
+            let mut email = claims.email.filter(|_| claims.email_verified == Some(true));
+            // some providers leave the email out of the ID token
+            if email.is_none() {
+                if let Some(userinfo) = &endpoints.userinfo {
+                    let info: serde_json::Value = client.get(userinfo).bearer_auth(&tokens.access_token).send().await
+                        .and_then(|response| response.error_for_status()).map_err(|e| e.to_string())?
+                        .json().await.map_err(|e| e.to_string())?;
+                    if info["sub"].as_str() == Some(claims.sub.as_str()) && info["email_verified"].as_bool() == Some(true) {
+                        email = info["email"].as_str().map(String::from);
+                    }
+                }
+            }
+            let username = claims.preferred_username.or(claims.nickname).or(claims.name);
+            Ok(ExternalProfile { subject: claims.sub, email, username })
+        },
+    }
+}
+
+// letters, digits, - and _ from the provider's username, with a number added until it is free
+async fn unique_username(db: &mongodb::Client, hint: Option<&str>) -> mongodb::error::Result<String> {
+    let mut base: String = normalize_username(hint.unwrap_or_default()).chars()
+        .filter(|c| allowed_character(*c))
+        .take(MAX_USERNAME_LENGTH - 4)
+        .collect();
+    // e.g. too short, reserved or ending in a dot after being cut down
+    if username_problem(&base).is_some() {
+        base = "user".to_string();
+    }
+    for number in 1..100 {
+        let candidate = if number == 1 { base.clone() } else { format!("{base}{number}") };
+        if find_user(db, &candidate).await?.is_none() {
+            return Ok(candidate);
+        }
+    }
+    Ok(format!("{base}-{}", random_token(3)))
+}
+
+// the session cookie is SameSite=Strict, so it isn't sent on the navigation the provider started,
+// a same-site hop through this page makes the next page see the new login
+fn continue_to(url: String, locale: &Locale) -> Template {
+    Template::render("oauth-continue", context! {
+        url,
+        lang: &locale.lang,
+    })
+}
+
+fn oauth_error(locale: &Locale, error: &str) -> Template {
+    Template::render("oauth-error", context! {
+        error,
+        lang: &locale.lang,
+    })
+}
+
+// a new account for someone signing in with a provider for the first time, Err is the message key to show
+async fn create_external_user(db: &mongodb::Client, config: &SiteConfig, key: &str, profile: ExternalProfile) -> Result<Result<User, &'static str>, Status> {
+    let email = profile.email.as_ref().map(|email| email.to_lowercase());
+    // nobody knows this password, a reset email can set one later
+    let password_hash = hash_password(&random_token(32), config).map_err(|_e| Status::InternalServerError)?;
+    let mut created = None;
+    for _ in 0..CREATE_USER_ATTEMPTS {
+        let username = unique_username(db, profile.username.as_deref()).await.map_err(|_e| Status::InternalServerError)?;
+        let user = User::external(username, password_hash.clone(), email.clone());
+        match db.users_coll().insert_one(&user, None).await {
+            Ok(_) => {
+                created = Some(user);
+                break;
+            },
+            // the username or email was taken between the checks and the insert, a taken username gets another go
+            Err(error) if is_duplicate_key(&error) => {
+                if let Some(email) = &email {
+                    if db.users_coll().count_documents(doc! {"email": email}, None).await.map_err(|_e| Status::InternalServerError)? > 0 {
+                        return Ok(Err("oauth.email_taken"));
+                    }
+                }
+            },
+            Err(_e) => return Err(Status::InternalServerError),
+        }
+    }
+    let user = match created {
+        Some(user) => user,
+        None => return Ok(Err("oauth.failed")),
+    };
+    let identity = ExternalIdentity { _id: ObjectId::new(), provider: key.to_string(), subject: profile.subject.clone(), user_id: user.id(), email: profile.email, created_time: bson::DateTime::now() };
+    if let Err(error) = db.external_identities_coll().insert_one(identity, None).await {
+        // nobody could ever sign in to the account without its identity
+        db.users_coll().delete_one(doc! {"_id": user.id()}, None).await.map_err(|_e| Status::InternalServerError)?;
+        if !is_duplicate_key(&error) {
+            return Err(Status::InternalServerError);
+        }
+        // another callback for the same account got there first, sign in to the user it made
+        let identity = db.external_identities_coll().find_one(doc! {"provider": key, "subject": &profile.subject}, None).await
+            .map_err(|_e| Status::InternalServerError)?;
+        let existing = match identity {
+            Some(identity) => db.users_coll().find_one(doc! {"_id": identity.user_id}, None).await.map_err(|_e| Status::InternalServerError)?,
+            None => None,
+        };
+        return Ok(existing.ok_or("oauth.failed"));
+    }
+    Ok(Ok(user))
+}
+
+// GET /auth/<provider>/callback?code=...&state=... where the provider sends the browser back to
+#[get("/auth/<provider>/callback?<code>&<state>&<error>")]
+async fn oauth_callback(provider: &str, code: Option<&str>, state: Option<&str>, error: Option<&str>, jar: &CookieJar<'_>, locale: Locale, session_client: SessionClient, db: Connection<MainDatabase>, client: &State<Client>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    let key = provider;
+    let provider = find_provider(config, key)?;
+    // the state has to match the cookie set when this browser left for the provider
+    let cookie_state = jar.get_private(STATE_COOKIE).map(|cookie| cookie.value().to_string());
+    jar.remove_private(Cookie::named(STATE_COOKIE));
+    let state = match (state, cookie_state) {
+        (Some(state), Some(cookie_state)) if state == cookie_state => state,
+        _ => return Ok(oauth_error(&locale, "oauth.invalid_state")),
+    };
+    let attempt = db.oauth_attempts_coll()
+        .find_one_and_delete(doc! {"state_hash": token_hash(state), "provider": key, "expires_time": {"$gt": bson::DateTime::now()}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    let attempt = match attempt {
+        Some(attempt) => attempt,
+        None => return Ok(oauth_error(&locale, "oauth.invalid_state")),
+    };
+    let code = match (code, error) {
+        (Some(code), None) => code,
+        // e.g. access_denied when the user said no
+        _ => return Ok(oauth_error(&locale, "oauth.cancelled")),
+    };
+    let profile = async {
+        let endpoints = provider.endpoints(client).await?;
+        let tokens = exchange_code(client, provider, &endpoints, config, key, code, &attempt.code_verifier).await?;
+        fetch_profile(client, provider, &endpoints, &tokens, &attempt.nonce).await
+    }.await;
+    let profile = match profile {
+        Ok(profile) => profile,
+        Err(error) => {
+            println!("{key}: {error}");
+            return Ok(oauth_error(&locale, "oauth.failed"));
+        },
+    };
+    let identity = db.external_identities_coll().find_one(doc! {"provider": key, "subject": &profile.subject}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+
+    // linking from /account/connections
+    if let Some(user_id) = attempt.link_user_id {
+        match identity {
+            Some(identity) if identity.user_id != user_id => return Ok(oauth_error(&locale, "oauth.linked_elsewhere")),
+            Some(_) => {},
+            None => {
+                let identity = ExternalIdentity { _id: ObjectId::new(), provider: key.to_string(), subject: profile.subject, user_id, email: profile.email, created_time: bson::DateTime::now() };
+                if let Err(error) = db.external_identities_coll().insert_one(identity, None).await {
+                    if is_duplicate_key(&error) {
+                        return Ok(oauth_error(&locale, "oauth.linked_elsewhere"));
+                    }
+                    return Err(Status::InternalServerError);
+                }
+            },
+        }
+        return Ok(continue_to(uri!(connections_page).to_string(), &locale));
+    }
+
+    let user = match identity {
+        Some(identity) => db.users_coll().find_one(doc! {"_id": identity.user_id}, None).await.map_err(|_e| Status::InternalServerError)?,
+        None => {
+            // an existing account has to link the identity itself, taking it over by email would let whoever
+            // controls the address at the provider into the account
+            if let Some(email) = &profile.email {
+                let email_taken = db.users_coll().count_documents(doc! {"email": email.to_lowercase()}, None).await.map_err(|_e| Status::InternalServerError)? > 0;
+                if email_taken {
+                    return Ok(oauth_error(&locale, "oauth.email_taken"));
+                }
+            }
+            match create_external_user(&db, config, key, profile).await? {
+                Ok(user) => Some(user),
+                Err(error) => return Ok(oauth_error(&locale, error)),
+            }
+        },
+    };
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(oauth_error(&locale, "oauth.failed")),
+    };
+    // two-factor still applies to social logins
+    if user.totp_secret().is_some() {
+        start_challenge(&db, jar, user.id(), attempt.next_page.as_deref()).await.map_err(|_e| Status::InternalServerError)?;
+        return Ok(continue_to(uri!(login_two_factor).to_string(), &locale));
+    }
+    start_session(&db, jar, user.id(), &session_client).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(continue_to(after_login_url(attempt.next_page.as_deref()), &locale))
+}
+
+#[derive(Serialize)]
+struct LinkedAccount {
+    id: String,
+    provider: String,
+    name: String,
+    email: Option<String>,
+    linked: String,
+}
+
+// /account/connections, external accounts that can be used to log in
+#[get("/account/connections")]
+async fn connections_page(user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    let identities: Vec<ExternalIdentity> = db.external_identities_coll().find(doc! {"user_id": user.id}, None).await
+        .map_err(|_e| Status::InternalServerError)?
+        .try_collect().await.map_err(|_e| Status::InternalServerError)?;
+    let connections: Vec<LinkedAccount> = identities.iter().map(|identity| LinkedAccount {
+        id: identity._id.to_hex(),
+        provider: identity.provider.clone(),
+        name: config.oauth.get(&identity.provider).map_or_else(|| identity.provider.clone(), |provider| provider.name.clone()),
+        email: identity.email.clone(),
+        linked: identity.created_time.to_chrono().format("%Y-%b-%d").to_string(),
+    }).collect();
+    let unlinked: BTreeMap<String, String> = provider_names(config).into_iter()
+        .filter(|(key, _)| !identities.iter().any(|identity| &identity.provider == key))
+        .collect();
+    Ok(Template::render("connections", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        connections,
+        unlinked,
+    }))
+}
+
+#[derive(FromForm)]
+struct UnlinkData {
+    authenticity_token: String,
+}
+
+#[post("/account/connections/<id>/unlink", data = "<form>")]
+async fn unlink_identity(id: &str, form: Form<UnlinkData>, csrf_token: CsrfToken, user: AuthenticatedUser, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let id = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    // an account made through a provider with no email can't get a password by email, keep its last way in
+    let account = db.users_coll().find_one(doc! {"_id": user.id}, None).await.map_err(|_e| Status::InternalServerError)?.ok_or(Status::Unauthorized)?;
+    let linked = db.external_identities_coll().count_documents(doc! {"user_id": user.id}, None).await.map_err(|_e| Status::InternalServerError)?;
+    if account.email().is_none() && linked <= 1 {
+        return Err(Status::Conflict);
+    }
+    // matching on user_id too so nobody can unlink someone else's account
+    let deleted = db.external_identities_coll().delete_one(doc! {"_id": id, "user_id": user.id}, None).await
+        .map_err(|_e| Status::InternalServerError)?.deleted_count;
+    if deleted == 0 {
+        return Err(Status::NotFound);
+    }
+    Ok(Redirect::to(uri!(connections_page)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: &str = "n-0S6_WzA2Mj";
+
+    fn provider() -> OAuthProvider {
+        OAuthProvider {
+            kind: OAuthKind::Oidc,
+            name: "Mock".to_string(),
+            client_id: "blog".to_string(),
+            client_secret: "secret".to_string(),
+            issuer: Some("http://localhost:8080/default".to_string()),
+            scopes: None,
+        }
+    }
+
+    fn endpoints() -> Endpoints {
+        Endpoints {
+            authorization: "http://localhost:8080/default/authorize".to_string(),
+            token: "http://localhost:8080/default/token".to_string(),
+            userinfo: None,
+            issuer: Some("http://localhost:8080/default".to_string()),
+        }
+    }
+
+    // the signature isn't checked, the token comes straight from the provider over TLS
+    fn id_token(claims: serde_json::Value) -> String {
+        let header = BASE64URL_NOPAD.encode(br#"{"alg":"RS256"}"#);
+        let payload = BASE64URL_NOPAD.encode(claims.to_string().as_bytes());
+        format!("{header}.{payload}.signature")
+    }
+
+    fn claims() -> serde_json::Value {
+        serde_json::json!({
+            "iss": "http://localhost:8080/default",
+            "sub": "user-1",
+            "aud": "blog",
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": NONCE,
+            "email": "someone@example.com",
+            "email_verified": true,
+        })
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc_7636() {
+        assert_eq!(code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn id_token_claims_accepts_a_valid_token() {
+        let claims = id_token_claims(&id_token(claims()), &provider(), &endpoints(), NONCE).unwrap();
+        assert_eq!(claims.sub, "user-1");
+        assert_eq!(claims.email.as_deref(), Some("someone@example.com"));
+    }
+
+    #[test]
+    fn id_token_claims_accepts_an_audience_list() {
+        let mut claims = claims();
+        claims["aud"] = serde_json::json!(["other", "blog"]);
+        assert!(id_token_claims(&id_token(claims), &provider(), &endpoints(), NONCE).is_ok());
+    }
+
+    #[test]
+    fn id_token_claims_rejects_a_wrong_issuer() {
+        let mut claims = claims();
+        claims["iss"] = serde_json::json!("https://evil.example.com");
+        assert!(id_token_claims(&id_token(claims), &provider(), &endpoints(), NONCE).is_err());
+    }
+
+    #[test]
+    fn id_token_claims_rejects_a_wrong_audience() {
+        let mut claims = claims();
+        claims["aud"] = serde_json::json!("another-client");
+        assert!(id_token_claims(&id_token(claims), &provider(), &endpoints(), NONCE).is_err());
+    }
+
+    #[test]
+    fn id_token_claims_rejects_a_wrong_nonce() {
+        assert!(id_token_claims(&id_token(claims()), &provider(), &endpoints(), "another-nonce").is_err());
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert!(id_token_claims(&id_token(claims), &provider(), &endpoints(), NONCE).is_err());
+    }
+
+    #[test]
+    fn id_token_claims_rejects_an_expired_token() {
+        let mut claims = claims();
+        claims["exp"] = serde_json::json!(Utc::now().timestamp() - 60);
+        assert!(id_token_claims(&id_token(claims), &provider(), &endpoints(), NONCE).is_err());
+    }
+
+    #[test]
+    fn id_token_claims_rejects_a_malformed_token() {
+        assert!(id_token_claims("not-a-token", &provider(), &endpoints(), NONCE).is_err());
+    }
+}
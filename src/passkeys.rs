@@ -0,0 +1,490 @@
+// WebAuthn passkeys, a passwordless login next to the password flow in users.rs
+// the registration and login ceremonies are JSON endpoints under BASE driven by static/passkeys.js,
+// their state is kept in MongoDB between the start and finish calls
+// to try it without a security key use a virtual authenticator e.g. Chrome DevTools > WebAuthn,
+// the tests at the bottom go through both ceremonies with a software authenticator
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use data_encoding::BASE64URL_NOPAD;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use rocket::{fairing, form::Form, Build, Rocket, Route, State, http::{uri::Origin, Cookie, CookieJar, Status}, response::Redirect, futures::TryStreamExt};
+use rocket::serde::json::{Json, serde_json::{self, json}};
+use rocket_db_pools::mongodb;
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use webauthn_rs::prelude::*;
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::errors::{ApiError, ApiResult};
+use crate::login_attempts::{self, FailureReason};
+use crate::sessions::{SessionClient, start_session};
+use crate::usernames::normalize_username;
+use crate::users::{AuthenticatedUser, after_login_url, find_user};
+use crate::utils::{is_duplicate_key, random_token, token_hash};
+
+pub const BASE: Origin<'static> = uri!("/api/passkeys");
+pub const CEREMONY_COOKIE: &str = "passkey_ceremony";
+// how long the browser has between the start and finish calls
+const CEREMONY_MINUTES: i64 = 5;
+const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+// the same for real and decoy login options
+const CHALLENGE_BYTES: usize = 32;
+const AUTHENTICATION_TIMEOUT_MS: u32 = 60000;
+
+// the account page, mounted at "/"
+pub fn routes() -> Vec<Route> {
+    routes![passkeys_page, delete_passkey]
+}
+
+// the ceremonies, mounted at BASE with the JSON catchers
+pub fn api_routes() -> Vec<Route> {
+    routes![register_start, register_finish, login_start, login_finish]
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasskeyCredential {
+    pub _id: ObjectId,
+    pub user_id: ObjectId,
+    // base64url credential id, unique across all users
+    pub credential_id: String,
+    // chosen by the user so they can tell their passkeys apart
+    pub name: String,
+    pub passkey: Passkey,
+    pub created_time: bson::DateTime,
+    pub last_used: Option<bson::DateTime>,
+}
+
+// a ceremony between its start and finish calls, removed by a TTL index
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasskeyCeremony {
+    pub _id: ObjectId,
+    // sha-256 of the token in the passkey_ceremony cookie
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub registration: Option<PasskeyRegistration>,
+    pub authentication: Option<PasskeyAuthentication>,
+    pub next_page: Option<String>,
+    pub expires_time: bson::DateTime,
+}
+
+// made up login options for usernames that can't log in with a passkey, see decoy_options
+pub struct PasskeyDecoys {
+    key: Vec<u8>,
+    rp_id: String,
+}
+
+// the relying party is the host of site_url, passkeys only work on that origin
+fn relying_party(site_url: &str, site_name: &str) -> Option<(Webauthn, String)> {
+    let origin = Url::parse(site_url).ok()?;
+    let rp_id = origin.host_str()?.to_string();
+    let webauthn = WebauthnBuilder::new(&rp_id, &origin).ok()?
+        .rp_name(site_name)
+        .timeout(std::time::Duration::from_millis(AUTHENTICATION_TIMEOUT_MS.into()))
+        .build().ok()?;
+    Some((webauthn, rp_id))
+}
+
+pub async fn init(rocket: Rocket<Build>) -> fairing::Result {
+    let config = match rocket.state::<SiteConfig>() {
+        Some(config) => config,
+        None => return Err(rocket),
+    };
+    let (webauthn, rp_id) = match relying_party(&config.site_url, &config.site_name) {
+        Some(relying_party) => relying_party,
+        None => {
+            println!("could not set up passkeys for site_url {}", config.site_url);
+            return Err(rocket);
+        },
+    };
+    // derived from secret_key so the decoys stay the same across restarts, like real credential ids do
+    let secret = rocket.figment().extract_inner::<String>("secret_key").unwrap_or_else(|_e| random_token(32));
+    let decoys = PasskeyDecoys { key: Sha256::digest(format!("passkey decoys {secret}").as_bytes()).to_vec(), rp_id };
+    Ok(rocket.manage(webauthn).manage(decoys))
+}
+
+// WebAuthn wants a 16 byte user handle, the ObjectId padded with zeros is stable and unique
+fn user_handle(user_id: ObjectId) -> Uuid {
+    let mut bytes = [0u8; 16];
+    bytes[..12].copy_from_slice(&user_id.bytes());
+    Uuid::from_bytes(bytes)
+}
+
+fn credential_id(id: &CredentialID) -> String {
+    id.to_string()
+}
+
+async fn user_passkeys(db: &mongodb::Client, user_id: ObjectId) -> mongodb::error::Result<Vec<PasskeyCredential>> {
+    db.passkeys_coll().find(doc! {"user_id": user_id}, None).await?.try_collect().await
+}
+
+async fn start_ceremony(db: &mongodb::Client, jar: &CookieJar<'_>, mut ceremony: PasskeyCeremony) -> mongodb::error::Result<()> {
+    let token = random_token(32);
+    ceremony.token_hash = token_hash(&token);
+    db.passkey_ceremonies_coll().insert_one(ceremony, None).await?;
+    jar.add_private(Cookie::new(CEREMONY_COOKIE, token));
+    Ok(())
+}
+
+// each ceremony can only be finished once
+async fn take_ceremony(db: &mongodb::Client, jar: &CookieJar<'_>) -> mongodb::error::Result<Option<PasskeyCeremony>> {
+    let token = match jar.get_private(CEREMONY_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Ok(None),
+    };
+    jar.remove_private(Cookie::named(CEREMONY_COOKIE));
+    let filter = doc! {"token_hash": token_hash(&token), "expires_time": {"$gt": bson::DateTime::now()}};
+    db.passkey_ceremonies_coll().find_one_and_delete(filter, None).await
+}
+
+fn new_ceremony(user_id: ObjectId) -> PasskeyCeremony {
+    PasskeyCeremony {
+        _id: ObjectId::new(),
+        token_hash: String::new(),
+        user_id,
+        registration: None,
+        authentication: None,
+        next_page: None,
+        expires_time: bson::DateTime::from_chrono(Utc::now() + Duration::minutes(CEREMONY_MINUTES)),
+    }
+}
+
+// what login_start answers for an unknown username or an account without passkeys, shaped like the real options
+// the credential id is an HMAC of the username, so asking again gives the same answer as it would for a real account
+fn decoy_options(decoys: &PasskeyDecoys, username: &str) -> serde_json::Result<RequestChallengeResponse> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&decoys.key).expect("HMAC takes keys of any length");
+    mac.update(username.as_bytes());
+    let credential_id = mac.finalize().into_bytes();
+    let mut challenge = [0u8; CHALLENGE_BYTES];
+    OsRng.fill_bytes(&mut challenge);
+    serde_json::from_value(json!({
+        "publicKey": {
+            "challenge": BASE64URL_NOPAD.encode(&challenge),
+            "timeout": AUTHENTICATION_TIMEOUT_MS,
+            "rpId": decoys.rp_id,
+            "allowCredentials": [{"type": "public-key", "id": BASE64URL_NOPAD.encode(&credential_id)}],
+            "userVerification": "required",
+        },
+    }))
+}
+
+fn ceremony_failed(error: WebauthnError) -> ApiError {
+    println!("{error:?}");
+    ApiError::new(Status::BadRequest, "the passkey could not be verified")
+}
+
+fn registration_ceremony(webauthn: &Webauthn, user_id: ObjectId, username: &str, existing: Vec<CredentialID>) -> Result<(CreationChallengeResponse, PasskeyCeremony), WebauthnError> {
+    let (options, registration) = webauthn.start_passkey_registration(user_handle(user_id), username, username, Some(existing))?;
+    let mut ceremony = new_ceremony(user_id);
+    ceremony.registration = Some(registration);
+    Ok((options, ceremony))
+}
+
+// the passkey the browser made, if it answers a registration this user started
+fn registered_passkey(webauthn: &Webauthn, ceremony: Option<PasskeyCeremony>, user_id: ObjectId, credential: &RegisterPublicKeyCredential) -> Result<Passkey, ApiError> {
+    let registration = match ceremony {
+        Some(PasskeyCeremony { user_id: started_by, registration: Some(registration), .. }) if started_by == user_id => registration,
+        _ => return Err(ApiError::new(Status::BadRequest, "no passkey registration in progress")),
+    };
+    webauthn.finish_passkey_registration(credential, &registration).map_err(ceremony_failed)
+}
+
+fn authentication_ceremony(webauthn: &Webauthn, user_id: ObjectId, passkeys: &[Passkey], next_page: Option<String>) -> Result<(RequestChallengeResponse, PasskeyCeremony), WebauthnError> {
+    let (mut options, authentication) = webauthn.start_passkey_authentication(passkeys)?;
+    // decoys can't know which transports a passkey uses, so real options go without them too
+    options.public_key.allow_credentials.iter_mut().for_each(|allowed| allowed.transports = None);
+    let mut ceremony = new_ceremony(user_id);
+    ceremony.authentication = Some(authentication);
+    ceremony.next_page = next_page;
+    Ok((options, ceremony))
+}
+
+// POST /api/passkeys/register/start, the options for navigator.credentials.create()
+#[post("/register/start", format = "json")]
+async fn register_start(user: AuthenticatedUser, jar: &CookieJar<'_>, db: Connection<MainDatabase>, webauthn: &State<Webauthn>) -> ApiResult<CreationChallengeResponse> {
+    // the same authenticator can't be registered twice
+    let existing: Vec<CredentialID> = user_passkeys(&db, user.id).await?.iter().map(|credential| credential.passkey.cred_id().clone()).collect();
+    let (options, ceremony) = registration_ceremony(webauthn, user.id, &user.username, existing).map_err(ceremony_failed)?;
+    start_ceremony(&db, jar, ceremony).await?;
+    Ok(Json(options))
+}
+
+#[derive(Deserialize)]
+struct RegisterInput {
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize)]
+struct PasskeyInfo {
+    id: String,
+    name: String,
+    created: String,
+    last_used: Option<String>,
+}
+
+impl PasskeyCredential {
+    fn new(user_id: ObjectId, name: &str, passkey: Passkey) -> PasskeyCredential {
+        PasskeyCredential {
+            _id: ObjectId::new(),
+            user_id,
+            credential_id: credential_id(passkey.cred_id()),
+            name: name.to_string(),
+            passkey,
+            created_time: bson::DateTime::now(),
+            last_used: None,
+        }
+    }
+
+    fn info(&self) -> PasskeyInfo {
+        PasskeyInfo {
+            id: self._id.to_hex(),
+            name: self.name.clone(),
+            created: self.created_time.to_chrono().format("%Y-%b-%d").to_string(),
+            last_used: self.last_used.map(|last_used| last_used.to_chrono().format("%Y-%b-%d").to_string()),
+        }
+    }
+}
+
+// POST /api/passkeys/register/finish, stores the new passkey
+#[post("/register/finish", format = "json", data = "<input>")]
+async fn register_finish(input: Json<RegisterInput>, user: AuthenticatedUser, jar: &CookieJar<'_>, db: Connection<MainDatabase>, webauthn: &State<Webauthn>) -> ApiResult<PasskeyInfo> {
+    let name = input.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_PASSKEY_NAME_LENGTH {
+        return Err(ApiError::new(Status::UnprocessableEntity, format!("name must be 1 to {MAX_PASSKEY_NAME_LENGTH} characters")));
+    }
+    let ceremony = take_ceremony(&db, jar).await?;
+    let passkey = registered_passkey(webauthn, ceremony, user.id, &input.credential)?;
+    let credential = PasskeyCredential::new(user.id, name, passkey);
+    if let Err(error) = db.passkeys_coll().insert_one(&credential, None).await {
+        if is_duplicate_key(&error) {
+            return Err(ApiError::new(Status::Conflict, "that passkey is already registered"));
+        }
+        return Err(error.into());
+    }
+    Ok(Json(credential.info()))
+}
+
+#[derive(Deserialize)]
+struct LoginInput {
+    username: String,
+    next: Option<String>,
+}
+
+// POST /api/passkeys/login/start, the options for navigator.credentials.get()
+#[post("/login/start", format = "json", data = "<input>")]
+async fn login_start(input: Json<LoginInput>, jar: &CookieJar<'_>, client: SessionClient, db: Connection<MainDatabase>, webauthn: &State<Webauthn>, decoys: &State<PasskeyDecoys>) -> ApiResult<RequestChallengeResponse> {
+    // the same limits as the password login in users.rs
+    let username = normalize_username(&input.username);
+    if login_attempts::blocked_until(&db, &username, client.ip.as_deref()).await?.is_some() {
+        login_attempts::record_failure(&db, &username, None, &client, FailureReason::Throttled).await?;
+        return Err(ApiError::new(Status::TooManyRequests, "too many failed logins, please wait a few minutes and try again"));
+    }
+    let user = find_user(&db, &input.username).await?;
+    let passkeys: Vec<Passkey> = match &user {
+        Some(user) => user_passkeys(&db, user.id()).await?.into_iter().map(|credential| credential.passkey).collect(),
+        None => Vec::new(),
+    };
+    // an unknown username or an account without passkeys gets decoy options and a ceremony cookie like everyone else,
+    // so this can't be used to find accounts, the browser just won't find a passkey for them
+    let user = match user {
+        Some(user) if !passkeys.is_empty() => user,
+        _ => {
+            jar.add_private(Cookie::new(CEREMONY_COOKIE, random_token(32)));
+            return Ok(Json(decoy_options(decoys, &username).map_err(|_e| ApiError::internal())?));
+        },
+    };
+    let (options, ceremony) = authentication_ceremony(webauthn, user.id(), &passkeys, input.next.clone()).map_err(ceremony_failed)?;
+    start_ceremony(&db, jar, ceremony).await?;
+    Ok(Json(options))
+}
+
+#[derive(Serialize)]
+struct LoggedIn {
+    // where the browser should go next
+    redirect: String,
+}
+
+// POST /api/passkeys/login/finish, starts a session like login_post does
+// a passkey already checks possession and a PIN or biometric, so the TOTP step is skipped
+#[post("/login/finish", format = "json", data = "<credential>")]
+async fn login_finish(credential: Json<PublicKeyCredential>, jar: &CookieJar<'_>, client: SessionClient, db: Connection<MainDatabase>, webauthn: &State<Webauthn>) -> ApiResult<LoggedIn> {
+    let (user_id, authentication, next_page) = match take_ceremony(&db, jar).await? {
+        Some(PasskeyCeremony { user_id, authentication: Some(authentication), next_page, .. }) => (user_id, authentication, next_page),
+        // also what a decoy ceremony from login_start ends in
+        _ => return Err(ApiError::new(Status::BadRequest, "the passkey could not be verified")),
+    };
+    let user = db.users_coll().find_one(doc! {"_id": user_id}, None).await?.ok_or_else(ApiError::not_found)?;
+    let username = normalize_username(user.username());
+    let result = match webauthn.finish_passkey_authentication(&credential, &authentication) {
+        Ok(result) => result,
+        Err(error) => {
+            login_attempts::record_failure(&db, &username, Some(user_id), &client, FailureReason::PasskeyFailed).await?;
+            return Err(ceremony_failed(error));
+        },
+    };
+    let filter = doc! {"user_id": user_id, "credential_id": credential_id(result.cred_id())};
+    let mut stored = db.passkeys_coll().find_one(filter, None).await?
+        .ok_or_else(|| ApiError::new(Status::BadRequest, "the passkey could not be verified"))?;
+    // keeps the signature counter current so cloned authenticators are caught
+    stored.passkey.update_credential(&result);
+    db.passkeys_coll().update_one(
+        doc! {"_id": stored._id},
+        doc! {"$set": {"passkey": bson::to_bson(&stored.passkey).map_err(|_e| ApiError::internal())?, "last_used": bson::DateTime::now()}},
+        None,
+    ).await?;
+    login_attempts::clear_failures(&db, &username).await?;
+    if !user.email_verified() {
+        return Err(ApiError::new(Status::Forbidden, "confirm your email address before logging in"));
+    }
+    start_session(&db, jar, user.id(), &client).await?;
+    Ok(Json(LoggedIn { redirect: after_login_url(next_page.as_deref()) }))
+}
+
+// /account/passkeys, add and remove passkeys
+#[get("/account/passkeys")]
+async fn passkeys_page(user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    let passkeys: Vec<PasskeyInfo> = user_passkeys(&db, user.id).await.map_err(|_e| Status::InternalServerError)?
+        .iter().map(PasskeyCredential::info).collect();
+    Ok(Template::render("passkeys", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        passkeys,
+    }))
+}
+
+#[derive(FromForm)]
+struct DeleteData {
+    authenticity_token: String,
+}
+
+#[post("/account/passkeys/<id>/delete", data = "<form>")]
+async fn delete_passkey(id: &str, form: Form<DeleteData>, csrf_token: CsrfToken, user: AuthenticatedUser, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let id = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    // matching on user_id too so nobody can remove someone else's passkey
+    let deleted = db.passkeys_coll().delete_one(doc! {"_id": id, "user_id": user.id}, None).await
+        .map_err(|_e| Status::InternalServerError)?.deleted_count;
+    if deleted == 0 {
+        return Err(Status::NotFound);
+    }
+    Ok(Redirect::to(uri!(passkeys_page)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webauthn_authenticator_rs::{WebauthnAuthenticator, softpasskey::SoftPasskey};
+
+    const SITE_URL: &str = "https://blog.example";
+
+    fn webauthn() -> Webauthn {
+        relying_party(SITE_URL, "Blog").unwrap().0
+    }
+
+    fn decoys() -> PasskeyDecoys {
+        PasskeyDecoys { key: b"test key".to_vec(), rp_id: "blog.example".to_string() }
+    }
+
+    // ceremonies and passkeys go through MongoDB between the calls
+    fn stored<T: Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+        bson::from_document(bson::to_document(value).unwrap()).unwrap()
+    }
+
+    // what register_start, register_finish, login_start and login_finish do apart from the database and cookies
+    #[test]
+    fn registers_and_logs_in_with_a_software_authenticator() {
+        let webauthn = webauthn();
+        let origin = Url::parse(SITE_URL).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let user_id = ObjectId::new();
+
+        let (options, ceremony) = registration_ceremony(&webauthn, user_id, "someone", Vec::new()).unwrap();
+        let response = authenticator.do_registration(origin.clone(), options).unwrap();
+        let passkey = registered_passkey(&webauthn, Some(stored(&ceremony)), user_id, &response).unwrap();
+        let credential = stored(&PasskeyCredential::new(user_id, "laptop", passkey));
+
+        let (options, ceremony) = authentication_ceremony(&webauthn, user_id, &[credential.passkey.clone()], Some("/blog".to_string())).unwrap();
+        let ceremony = stored(&ceremony);
+        assert_eq!(ceremony.next_page.as_deref(), Some("/blog"));
+        let response = authenticator.do_authentication(origin, options).unwrap();
+        let result = webauthn.finish_passkey_authentication(&response, ceremony.authentication.as_ref().unwrap()).unwrap();
+        assert_eq!(credential_id(result.cred_id()), credential.credential_id);
+    }
+
+    #[test]
+    fn registration_has_to_be_finished_by_the_user_who_started_it() {
+        let webauthn = webauthn();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let user_id = ObjectId::new();
+        let (options, ceremony) = registration_ceremony(&webauthn, user_id, "someone", Vec::new()).unwrap();
+        let response = authenticator.do_registration(Url::parse(SITE_URL).unwrap(), options).unwrap();
+        assert!(registered_passkey(&webauthn, Some(stored(&ceremony)), ObjectId::new(), &response).is_err());
+        assert!(registered_passkey(&webauthn, None, user_id, &response).is_err());
+    }
+
+    #[test]
+    fn login_fails_with_another_ceremony() {
+        let webauthn = webauthn();
+        let origin = Url::parse(SITE_URL).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let user_id = ObjectId::new();
+        let (options, ceremony) = registration_ceremony(&webauthn, user_id, "someone", Vec::new()).unwrap();
+        let response = authenticator.do_registration(origin.clone(), options).unwrap();
+        let passkey = registered_passkey(&webauthn, Some(ceremony), user_id, &response).unwrap();
+        let (options, _) = authentication_ceremony(&webauthn, user_id, &[passkey.clone()], None).unwrap();
+        let (_, other) = authentication_ceremony(&webauthn, user_id, &[passkey], None).unwrap();
+        let response = authenticator.do_authentication(origin, options).unwrap();
+        assert!(webauthn.finish_passkey_authentication(&response, other.authentication.as_ref().unwrap()).is_err());
+    }
+
+    // everything but the challenge and the credential ids has to match, or the decoys give themselves away
+    #[test]
+    fn decoy_options_look_like_real_ones() {
+        let webauthn = webauthn();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let user_id = ObjectId::new();
+        let (options, ceremony) = registration_ceremony(&webauthn, user_id, "someone", Vec::new()).unwrap();
+        let response = authenticator.do_registration(Url::parse(SITE_URL).unwrap(), options).unwrap();
+        let passkey = registered_passkey(&webauthn, Some(ceremony), user_id, &response).unwrap();
+        let (real, _) = authentication_ceremony(&webauthn, user_id, &[passkey], None).unwrap();
+        let decoy = decoy_options(&decoys(), "nobody").unwrap();
+        let without_random_parts = |options: &RequestChallengeResponse| {
+            let mut options = serde_json::to_value(options).unwrap();
+            options["publicKey"]["challenge"] = json!("");
+            for allowed in options["publicKey"]["allowCredentials"].as_array_mut().unwrap() {
+                allowed["id"] = json!("");
+            }
+            options
+        };
+        assert_eq!(without_random_parts(&decoy), without_random_parts(&real));
+    }
+
+    #[test]
+    fn decoy_credentials_are_stable_per_username() {
+        let ids = |username: &str| serde_json::to_value(decoy_options(&decoys(), username).unwrap()).unwrap()["publicKey"]["allowCredentials"].clone();
+        assert_eq!(ids("nobody"), ids("nobody"));
+        assert_ne!(ids("nobody"), ids("somebody"));
+        let challenge = |username: &str| serde_json::to_value(decoy_options(&decoys(), username).unwrap()).unwrap()["publicKey"]["challenge"].clone();
+        assert_ne!(challenge("nobody"), challenge("nobody"));
+    }
+
+    #[test]
+    fn user_handle_is_the_object_id_padded() {
+        let id = ObjectId::parse_str("64b7f0c2a1b2c3d4e5f60718").unwrap();
+        let handle = user_handle(id);
+        assert_eq!(&handle.as_bytes()[..12], &id.bytes());
+        assert_eq!(&handle.as_bytes()[12..], &[0u8; 4]);
+    }
+
+    #[test]
+    fn user_handle_is_stable_and_unique() {
+        let first = ObjectId::new();
+        let second = ObjectId::new();
+        assert_eq!(user_handle(first), user_handle(first));
+        assert_ne!(user_handle(first), user_handle(second));
+    }
+}
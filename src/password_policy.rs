@@ -0,0 +1,54 @@
+// rules a new password has to pass, shared by sign-up and password resets
+// the strength estimate is zxcvbn's 0-4 score, it also marks down passwords made from the username or email
+//
+// the breached password list is the Have I Been Pwned range format, one file per 5 character SHA-1 prefix
+// e.g. <breached_passwords_dir>/21BD1.txt holding SUFFIX:COUNT lines, like the k-anonymity API answers
+// `haveibeenpwned-downloader -s false <dir>` writes it, only the prefix file of the password is ever read
+use sha1::{Digest, Sha1};
+use data_encoding::HEXUPPER;
+use std::io::ErrorKind;
+use std::path::Path;
+use crate::config::SiteConfig;
+
+// argon2 happily hashes anything, but zxcvbn slows down a lot on very long input
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+const PREFIX_LENGTH: usize = 5;
+
+async fn breached(dir: &str, password: &str) -> std::io::Result<bool> {
+    let hash = HEXUPPER.encode(&Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+    let range = match tokio::fs::read_to_string(Path::new(dir).join(format!("{prefix}.txt"))).await {
+        Ok(range) => range,
+        // no file means nothing with that prefix was leaked
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error),
+    };
+    Ok(range.lines().any(|line| line.split(':').next().map_or(false, |line_suffix| line_suffix.trim().eq_ignore_ascii_case(suffix))))
+}
+
+// the message key from locales/ for what is wrong with the password, None when it is good enough
+// user_inputs are things like the username and email that shouldn't make up the password
+pub async fn password_problem(config: &SiteConfig, password: &str, user_inputs: &[&str]) -> Option<&'static str> {
+    let length = password.chars().count();
+    if length < config.password_min_length {
+        return Some("password.too_short");
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Some("password.too_long");
+    }
+    match zxcvbn::zxcvbn(password, user_inputs) {
+        Ok(estimate) if estimate.score() < config.password_min_strength => return Some("password.too_weak"),
+        Ok(_) => {},
+        // only happens for an empty password
+        Err(_e) => return Some("password.too_short"),
+    }
+    if let Some(dir) = &config.breached_passwords_dir {
+        match breached(dir, password).await {
+            Ok(true) => return Some("password.breached"),
+            Ok(false) => {},
+            // a missing or unreadable list shouldn't stop anyone signing up
+            Err(error) => println!("breached password list: {error}"),
+        }
+    }
+    None
+}
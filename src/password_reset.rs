@@ -0,0 +1,204 @@
+// forgotten password recovery, a single use link is emailed to the account's address
+// tokens are stored hashed, expire after password_reset_minutes and are kept until then so requests can be rate limited
+use std::sync::Arc;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use rocket::{form::Form, Route, State, http::Status, response::Redirect};
+use rocket_db_pools::mongodb;
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils, User};
+use crate::i18n::{Catalogs, Locale};
+use crate::mailer::{Email, Mailer};
+use crate::sessions::{SessionClient, revoke_sessions};
+use crate::password_policy::password_problem;
+use crate::users::{self, hash_password};
+use crate::utils::{random_token, token_hash};
+
+// reset emails allowed per account and per IP address in RATE_LIMIT_WINDOW_MINUTES
+const MAX_RESETS_PER_ACCOUNT: u64 = 3;
+const MAX_RESETS_PER_IP: u64 = 10;
+const RATE_LIMIT_WINDOW_MINUTES: i64 = 60;
+
+pub fn routes() -> Vec<Route> {
+    routes![forgot_password, forgot_password_new, forgot_password_post, reset_password, reset_password_new, reset_password_post]
+}
+
+// removed by a TTL index once expires_time passes
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasswordReset {
+    pub _id: ObjectId,
+    pub user_id: ObjectId,
+    // sha-256 of the token in the link
+    pub token_hash: String,
+    // who asked for it, for the per IP limit
+    pub ip: Option<String>,
+    pub created_time: bson::DateTime,
+    pub expires_time: bson::DateTime,
+    // set when the link is used, it can't be used again
+    pub used_time: Option<bson::DateTime>,
+}
+
+async fn under_rate_limit(db: &mongodb::Client, user: &User, ip: Option<&str>) -> mongodb::error::Result<bool> {
+    let since = bson::DateTime::from_chrono(Utc::now() - Duration::minutes(RATE_LIMIT_WINDOW_MINUTES));
+    let for_account = db.password_resets_coll().count_documents(doc! {"user_id": user.id(), "created_time": {"$gt": since}}, None).await?;
+    if for_account >= MAX_RESETS_PER_ACCOUNT {
+        return Ok(false);
+    }
+    if let Some(ip) = ip {
+        let for_ip = db.password_resets_coll().count_documents(doc! {"ip": ip, "created_time": {"$gt": since}}, None).await?;
+        if for_ip >= MAX_RESETS_PER_IP {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+async fn send_reset(db: &mongodb::Client, mailer: &dyn Mailer, config: &SiteConfig, catalogs: &Catalogs, user: &User, ip: Option<String>, lang: &str) -> Result<(), String> {
+    let email = match user.email() {
+        Some(email) => email.to_string(),
+        None => return Ok(()),
+    };
+    let token = random_token(32);
+    let now = Utc::now();
+    db.password_resets_coll().insert_one(PasswordReset {
+        _id: ObjectId::new(),
+        user_id: user.id(),
+        token_hash: token_hash(&token),
+        ip,
+        created_time: bson::DateTime::from_chrono(now),
+        expires_time: bson::DateTime::from_chrono(now + Duration::minutes(config.password_reset_minutes)),
+        used_time: None,
+    }, None).await.map_err(|e| e.to_string())?;
+    let link = config.absolute_url(uri!(reset_password(token.as_str())));
+    let minutes = config.password_reset_minutes.to_string();
+    let args = [("site_name", config.site_name.as_str()), ("username", user.username()), ("link", link.as_str()), ("minutes", minutes.as_str())];
+    let message = Email {
+        to: email,
+        subject: catalogs.format(lang, "email.reset.subject", &args),
+        body: catalogs.format(lang, "email.reset.body", &args),
+    };
+    mailer.send(message).await.map_err(|e| e.to_string())
+}
+
+// a reset that can still be used, without using it up
+async fn pending_reset(db: &mongodb::Client, token: &str) -> mongodb::error::Result<Option<PasswordReset>> {
+    db.password_resets_coll().find_one(doc! {"token_hash": token_hash(token), "used_time": null, "expires_time": {"$gt": bson::DateTime::now()}}, None).await
+}
+
+#[get("/forgot-password", rank = 1)]
+fn forgot_password(csrf_token: CsrfToken, locale: Locale) -> Template {
+    Template::render("forgot-password", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        lang: locale.lang,
+    })
+}
+
+#[get("/forgot-password", rank = 2)]
+fn forgot_password_new() -> Redirect {
+    Redirect::to(uri!(forgot_password))
+}
+
+#[derive(FromForm)]
+struct ForgotData<'r> {
+    authenticity_token: String,
+    email: &'r str,
+}
+
+#[post("/forgot-password", data = "<form>")]
+async fn forgot_password_post(form: Form<ForgotData<'_>>, csrf_token: CsrfToken, locale: Locale, client: SessionClient, db: Connection<MainDatabase>, mailer: &State<Box<dyn Mailer>>, config: &State<SiteConfig>, catalogs: &State<Arc<Catalogs>>) -> Result<Template, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let email = form.email.trim().to_lowercase();
+    let user = db.users_coll().find_one(doc! {"email": &email}, None).await.map_err(|_e| Status::InternalServerError)?;
+    if let Some(user) = user {
+        if under_rate_limit(&db, &user, client.ip.as_deref()).await.map_err(|_e| Status::InternalServerError)? {
+            if let Err(error) = send_reset(&db, mailer.inner().as_ref(), config, catalogs, &user, client.ip, &locale.lang).await {
+                println!("{error}");
+            }
+        }
+    }
+    // the same answer whether or not the address has an account
+    Ok(Template::render("forgot-password", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        sent: true,
+        lang: locale.lang,
+    }))
+}
+
+#[get("/reset-password/<token>", rank = 1)]
+pub async fn reset_password(token: &str, csrf_token: CsrfToken, locale: Locale, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    let valid = pending_reset(&db, token).await.map_err(|_e| Status::InternalServerError)?.is_some();
+    Ok(Template::render("reset-password", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        token,
+        error: if valid { None } else { Some("reset.invalid_link") },
+        valid,
+        password_min_length: config.password_min_length,
+        lang: locale.lang,
+    }))
+}
+
+#[get("/reset-password/<token>", rank = 2)]
+fn reset_password_new(token: &str) -> Redirect {
+    Redirect::to(uri!(reset_password(token)))
+}
+
+#[derive(FromForm)]
+struct ResetData<'r> {
+    authenticity_token: String,
+    password: &'r str,
+    password_confirm: &'r str,
+}
+
+#[post("/reset-password/<token>", data = "<form>")]
+async fn reset_password_post(token: &str, form: Form<ResetData<'_>>, csrf_token: CsrfToken, locale: Locale, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Result<Redirect, Template>, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Ok(Ok(Redirect::to(uri!(reset_password(token)))));
+    }
+    let reset_error = |error: &str, valid: bool| Template::render("reset-password", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        token,
+        error,
+        valid,
+        password_min_length: config.password_min_length,
+        lang: &locale.lang,
+    });
+    if form.password != form.password_confirm {
+        return Ok(Err(reset_error("reset.mismatch", true)));
+    }
+    let user_id = pending_reset(&db, token).await.map_err(|_e| Status::InternalServerError)?.map(|reset| reset.user_id);
+    let user = match user_id {
+        Some(user_id) => db.users_coll().find_one(doc! {"_id": user_id}, None).await.map_err(|_e| Status::InternalServerError)?,
+        None => None,
+    };
+    // checked before the link is used up so they can try another password
+    if let Some(user) = &user {
+        if let Some(problem) = password_problem(config, form.password, &[user.username(), user.email().unwrap_or_default()]).await {
+            return Ok(Err(reset_error(problem, true)));
+        }
+    }
+    // marking it used in the same step as finding it, so a link can't be used twice at once
+    let filter = doc! {"token_hash": token_hash(token), "used_time": null, "expires_time": {"$gt": bson::DateTime::now()}};
+    let reset = db.password_resets_coll().find_one_and_update(filter, doc! {"$set": {"used_time": bson::DateTime::now()}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    let reset = match reset {
+        Some(reset) => reset,
+        None => return Ok(Err(reset_error("reset.invalid_link", false))),
+    };
+    let password_hash = hash_password(form.password, config).map_err(|_e| Status::InternalServerError)?;
+    // the link was emailed to them, so following it also proves they own the address
+    db.users_coll().update_one(doc! {"_id": reset.user_id}, doc! {"$set": {"password": password_hash, "email_verified": true}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    // any other links that were sent are no good now, and whoever knew the old password is logged out
+    db.password_resets_coll().update_many(
+        doc! {"user_id": reset.user_id, "used_time": null},
+        doc! {"$set": {"used_time": bson::DateTime::now()}},
+        None,
+    ).await.map_err(|_e| Status::InternalServerError)?;
+    revoke_sessions(&db, reset.user_id, None).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Ok(Redirect::to(uri!(users::login(_)))))
+}
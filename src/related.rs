@@ -0,0 +1,109 @@
+// previous/next links and related posts for the post page
+// related posts are ranked when a post is saved and stored on it as related_ids, so showing them is one query
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use rocket::futures::TryStreamExt;
+use rocket_db_pools::mongodb::{self, options::{FindOneOptions, FindOptions}};
+use bson::{doc, oid::ObjectId, Document};
+use crate::blog::{BlogPost, PostCursor, newest_first, published_filter};
+use crate::databases::DatabaseUtils;
+use crate::search::text_index_missing;
+
+pub const RELATED_POSTS: usize = 5;
+// how many candidates each of the tag and text queries contributes before ranking
+const CANDIDATES: i64 = 20;
+// a shared tag is worth this much, the best text match is worth 1
+const TAG_WEIGHT: f64 = 1.0;
+
+// just enough of a post to link to it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PostLink {
+    pub _id: ObjectId,
+    pub title: String,
+    pub published_str: String,
+}
+
+fn link_projection() -> Document {
+    doc! {"title": 1, "published_str": 1}
+}
+
+// the published posts just before (older) and after (newer) this one
+pub async fn adjacent_posts(db: &mongodb::Client, post: &BlogPost) -> mongodb::error::Result<(Option<PostLink>, Option<PostLink>)> {
+    let links = db.posts_coll().clone_with_type::<PostLink>();
+    let cursor = PostCursor::of(post);
+    let older = links.find_one(
+        doc! {"$and": [published_filter(None), cursor.after_filter()]},
+        FindOneOptions::builder().sort(newest_first()).projection(link_projection()).build(),
+    ).await?;
+    let newer = links.find_one(
+        doc! {"$and": [published_filter(None), cursor.before_filter()]},
+        FindOneOptions::builder().sort(doc! {"published_time": 1, "_id": 1}).projection(link_projection()).build(),
+    ).await?;
+    Ok((older, newer))
+}
+
+// the stored related posts that are still published, in ranked order
+pub async fn related_posts(db: &mongodb::Client, post: &BlogPost) -> mongodb::error::Result<Vec<PostLink>> {
+    let ids = match &post.related_ids {
+        Some(ids) if !ids.is_empty() => ids,
+        _ => return Ok(Vec::new()),
+    };
+    let mut filter = published_filter(None);
+    filter.insert("_id", doc! {"$in": ids.clone()});
+    let find_options = FindOptions::builder().projection(link_projection()).build();
+    let mut links: Vec<PostLink> = db.posts_coll().clone_with_type::<PostLink>().find(filter, find_options).await?.try_collect().await?;
+    links.sort_by_key(|link| ids.iter().position(|id| *id == link._id));
+    Ok(links)
+}
+
+// ranks other published posts by shared tags plus text similarity of the title and tags
+pub async fn find_related(db: &mongodb::Client, post: &BlogPost) -> mongodb::error::Result<Vec<ObjectId>> {
+    let documents = db.posts_coll().clone_with_type::<Document>();
+    let mut scores: HashMap<ObjectId, f64> = HashMap::new();
+
+    if !post.tags.is_empty() {
+        let mut filter = published_filter(None);
+        filter.insert("_id", doc! {"$ne": post._id});
+        filter.insert("tags", doc! {"$in": post.tags.clone()});
+        let pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$project": {"shared": {"$size": {"$setIntersection": ["$tags", post.tags.clone()]}}, "published_time": 1}},
+            doc! {"$sort": {"shared": -1, "published_time": -1}},
+            doc! {"$limit": CANDIDATES},
+        ];
+        let shared: Vec<Document> = documents.aggregate(pipeline, None).await?.try_collect().await?;
+        for document in shared {
+            if let (Ok(id), Ok(count)) = (document.get_object_id("_id"), document.get_i32("shared")) {
+                *scores.entry(id).or_default() += TAG_WEIGHT * f64::from(count);
+            }
+        }
+    }
+
+    let mut filter = published_filter(None);
+    filter.insert("_id", doc! {"$ne": post._id});
+    filter.insert("$text", doc! {"$search": format!("{} {}", post.title, post.tags.join(" "))});
+    let find_options = FindOptions::builder()
+        .projection(doc! {"score": {"$meta": "textScore"}})
+        .sort(doc! {"score": {"$meta": "textScore"}})
+        .limit(CANDIDATES)
+        .build();
+    // without a text index posts are related by tags alone
+    let matches: Vec<Document> = match documents.find(filter, find_options).await {
+        Ok(cursor) => cursor.try_collect().await?,
+        Err(error) if text_index_missing(&error) => Vec::new(),
+        Err(error) => return Err(error),
+    };
+    let text_scores: Vec<(ObjectId, f64)> = matches.iter()
+        .filter_map(|document| Some((document.get_object_id("_id").ok()?, document.get_f64("score").ok()?)))
+        .collect();
+    let best = text_scores.iter().map(|(_, score)| *score).fold(0.0, f64::max);
+    if best > 0.0 {
+        for (id, score) in text_scores {
+            *scores.entry(id).or_default() += score / best;
+        }
+    }
+
+    let mut ranked: Vec<(ObjectId, f64)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    Ok(ranked.into_iter().take(RELATED_POSTS).map(|(id, _)| id).collect())
+}
@@ -0,0 +1,162 @@
+// immutable revision history of blog posts, mounted under blog::BASE
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use rocket_csrf::CsrfToken;
+use rocket::{form::Form, Route, http::Status, response::Redirect, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::FindOptions};
+use rocket_dyn_templates::{Template, context};
+use bson::{self, doc, oid::ObjectId};
+use crate::blog::{self, BlogPost, PostAuthor, PostDraft, can_manage, find_post, save_edit};
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::users::AuthenticatedUser;
+
+pub fn routes() -> Vec<Route> {
+    routes![post_revisions, restore_revision]
+}
+
+// a snapshot of a post taken every time it is saved, never modified afterwards
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Revision {
+    pub _id: ObjectId,
+    pub post_id: ObjectId,
+    // who saved this version, None for the baseline of posts written before revisions existed
+    pub editor: Option<PostAuthor>,
+    pub created_time: bson::DateTime,
+    pub created_str: String,
+    pub title: String,
+    pub content: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub cover_image: Option<String>,
+    // set when this revision was made by restoring an older one
+    pub restored_from: Option<ObjectId>,
+}
+
+impl Revision {
+    fn draft(&self) -> PostDraft {
+        PostDraft {
+            title: self.title.clone(),
+            content: self.content.clone(),
+            tags: self.tags.clone(),
+            published_time: None,
+            summary: self.summary.clone(),
+            cover_image: self.cover_image.clone(),
+            lang: None,
+            translation_group: None,
+            updated_time: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DiffLine {
+    // "insert", "delete" or "equal"
+    kind: &'static str,
+    text: String,
+}
+
+#[derive(FromForm)]
+struct RestoreData {
+    authenticity_token: String,
+}
+
+// writes a revision of the post as it is now
+pub async fn record_revision(db: &mongodb::Client, post: &BlogPost, editor: Option<&PostAuthor>, restored_from: Option<ObjectId>) -> mongodb::error::Result<Revision> {
+    let created_time = bson::DateTime::now();
+    let revision = Revision {
+        _id: ObjectId::new(),
+        post_id: post._id,
+        editor: editor.cloned(),
+        created_time,
+        created_str: created_time.to_chrono().format("%Y-%b-%d %H:%M:%S").to_string(),
+        title: post.title.clone(),
+        content: post.content.clone(),
+        tags: post.tags.clone(),
+        summary: post.summary.clone(),
+        cover_image: post.cover_image.clone(),
+        restored_from,
+    };
+    db.revisions_coll().insert_one(&revision, None).await?;
+    Ok(revision)
+}
+
+// posts created before revisions were recorded get their current state saved before the first edit
+pub async fn ensure_baseline(db: &mongodb::Client, post: &BlogPost) -> mongodb::error::Result<()> {
+    if db.revisions_coll().count_documents(doc! {"post_id": post._id}, None).await? == 0 {
+        record_revision(db, post, post.author.as_ref(), None).await?;
+    }
+    Ok(())
+}
+
+// newest first
+pub async fn list_revisions(db: &mongodb::Client, post_id: ObjectId) -> mongodb::error::Result<Vec<Revision>> {
+    let find_options = FindOptions::builder().sort(doc! {"created_time": -1, "_id": -1}).build();
+    db.revisions_coll().find(doc! {"post_id": post_id}, find_options).await?.try_collect().await
+}
+
+fn line_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    TextDiff::from_lines(old, new).iter_all_changes().map(|change| DiffLine {
+        kind: match change.tag() {
+            ChangeTag::Insert => "insert",
+            ChangeTag::Delete => "delete",
+            ChangeTag::Equal => "equal",
+        },
+        text: change.value().trim_end_matches(['\r', '\n']).to_string(),
+    }).collect()
+}
+
+async fn managed_post(db: &mongodb::Client, id: &str, user: &AuthenticatedUser) -> Result<BlogPost, Status> {
+    let oid = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    let post = find_post(db, oid).await.map_err(|_e| Status::InternalServerError)?.ok_or(Status::NotFound)?;
+    if !can_manage(user, &post) {
+        return Err(Status::Forbidden);
+    }
+    Ok(post)
+}
+
+// /blog/posts/<id>/revisions?from=<revision id>&to=<revision id>
+// without from and to, the latest revision is compared with the one before it
+#[get("/posts/<id>/revisions?<from>&<to>")]
+async fn post_revisions(id: &str, from: Option<&str>, to: Option<&str>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    let post = managed_post(&db, id, &user).await?;
+    let revisions = list_revisions(&db, post._id).await.map_err(|_e| Status::InternalServerError)?;
+    let find_revision = |revision_id: Option<&str>, default: Option<&Revision>| -> Result<Option<Revision>, Status> {
+        match revision_id {
+            Some(revision_id) => revisions.iter().find(|revision| revision._id.to_hex() == revision_id).cloned().map(Some).ok_or(Status::NotFound),
+            None => Ok(default.cloned()),
+        }
+    };
+    let to = find_revision(to, revisions.first())?;
+    let from = find_revision(from, revisions.get(1))?;
+    let diff = match (&from, &to) {
+        (Some(from), Some(to)) => line_diff(&from.content, &to.content),
+        _ => Vec::new(),
+    };
+    Ok(Template::render("blog/revisions", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        post,
+        revisions: &revisions,
+        from,
+        to,
+        diff,
+    }))
+}
+
+// restoring saves the old content as a new revision instead of rewriting history
+#[post("/posts/<id>/revisions/<revision_id>/restore", data = "<form>")]
+async fn restore_revision(id: &str, revision_id: &str, form: Form<RestoreData>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let post = managed_post(&db, id, &user).await?;
+    let revision_oid = ObjectId::parse_str(revision_id).map_err(|_e| Status::NotFound)?;
+    let revision = db.revisions_coll().find_one(doc! {"_id": revision_oid, "post_id": post._id}, None).await
+        .map_err(|_e| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?;
+    save_edit(&db, post._id, revision.draft(), Some(PostAuthor::from(&user)), Some(revision._id)).await
+        .map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(blog::BASE, post_revisions(id, _, _))))
+}
@@ -0,0 +1,159 @@
+// full-text search across blog posts, mounted under blog::BASE
+use rocket::{Route, State, http::Status, futures::TryStreamExt};
+use rocket::serde::json::Json;
+use rocket_db_pools::mongodb::{self, error::ErrorKind, options::FindOptions};
+use rocket_dyn_templates::{Template, context};
+use serde::Serialize;
+use bson::{doc, Document};
+use crate::blog::{self, BlogPost, published_filter};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::i18n::Locale;
+use crate::seo::PageMeta;
+use crate::utils::{xml_escape, regex_escape};
+
+pub const SEARCH_PAGE_SIZE: u64 = 10;
+// number of words shown around the first match
+const SNIPPET_WORDS_BEFORE: usize = 15;
+const SNIPPET_WORDS_AFTER: usize = 35;
+
+pub fn routes() -> Vec<Route> {
+    routes![search_page, search_api]
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    post: BlogPost,
+    // only available when the text index was used
+    score: Option<f64>,
+    // html with matches wrapped in <mark>
+    snippet: String,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    query: String,
+    page: u64,
+    total: u64,
+    pages: u64,
+    // false when the regex fallback was used
+    ranked: bool,
+    results: Vec<SearchHit>,
+}
+
+// the words we highlight, ignoring $text syntax such as negations and phrases
+fn search_terms(query: &str) -> Vec<String> {
+    query.split_whitespace()
+        .filter(|term| !term.starts_with('-'))
+        .map(|term| term.trim_matches('"').to_lowercase())
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+fn is_match(word: &str, terms: &[String]) -> bool {
+    let word = word.to_lowercase();
+    terms.iter().any(|term| word.contains(term.as_str()))
+}
+
+// a window of escaped text around the first match with every matching word highlighted
+fn snippet(content: &str, terms: &[String]) -> String {
+    let words: Vec<&str> = content.split_whitespace().collect();
+    let first_match = words.iter().position(|word| is_match(word, terms)).unwrap_or(0);
+    let start = first_match.saturating_sub(SNIPPET_WORDS_BEFORE);
+    let end = (first_match + SNIPPET_WORDS_AFTER).min(words.len());
+    let mut html = String::new();
+    if start > 0 {
+        html.push_str("&hellip; ");
+    }
+    let highlighted: Vec<String> = words[start..end].iter().map(|word| {
+        if is_match(word, terms) {
+            format!("<mark>{}</mark>", xml_escape(word))
+        } else {
+            xml_escape(word)
+        }
+    }).collect();
+    html.push_str(&highlighted.join(" "));
+    if end < words.len() {
+        html.push_str(" &hellip;");
+    }
+    html
+}
+
+// IndexNotFound is returned by $text queries when the collection has no text index
+pub fn text_index_missing(error: &mongodb::error::Error) -> bool {
+    matches!(*error.kind, ErrorKind::Command(ref command_error) if command_error.code == 27)
+}
+
+// relevance ranked search using the text index created in databases::create_indexes
+async fn text_search(db: &mongodb::Client, query: &str, skip: u64) -> mongodb::error::Result<(u64, Vec<(BlogPost, Option<f64>)>)> {
+    let mut filter = published_filter(None);
+    filter.insert("$text", doc! {"$search": query});
+    let total = db.posts_coll().count_documents(filter.clone(), None).await?;
+    let find_options = FindOptions::builder()
+        .projection(doc! {"score": {"$meta": "textScore"}})
+        .sort(doc! {"score": {"$meta": "textScore"}, "published_time": -1})
+        .skip(skip)
+        .limit(SEARCH_PAGE_SIZE as i64)
+        .build();
+    let documents: Vec<Document> = db.posts_coll().clone_with_type::<Document>().find(filter, find_options).await?.try_collect().await?;
+    let mut posts = Vec::with_capacity(documents.len());
+    for mut document in documents {
+        let score = document.remove("score").and_then(|score| score.as_f64());
+        posts.push((bson::from_document::<BlogPost>(document)?, score));
+    }
+    Ok((total, posts))
+}
+
+// case-insensitive substring search for deployments without a text index, newest first
+async fn regex_search(db: &mongodb::Client, query: &str, skip: u64) -> mongodb::error::Result<(u64, Vec<(BlogPost, Option<f64>)>)> {
+    let pattern = search_terms(query).iter().map(|term| regex_escape(term)).collect::<Vec<String>>().join("|");
+    let mut filter = published_filter(None);
+    filter.insert("$or", vec![
+        doc! {"title": {"$regex": pattern.as_str(), "$options": "i"}},
+        doc! {"content": {"$regex": pattern.as_str(), "$options": "i"}},
+    ]);
+    let total = db.posts_coll().count_documents(filter.clone(), None).await?;
+    let find_options = FindOptions::builder()
+        .sort(doc! {"published_time": -1})
+        .skip(skip)
+        .limit(SEARCH_PAGE_SIZE as i64)
+        .build();
+    let posts: Vec<BlogPost> = db.posts_coll().find(filter, find_options).await?.try_collect().await?;
+    Ok((total, posts.into_iter().map(|post| (post, None)).collect()))
+}
+
+async fn search(db: &mongodb::Client, config: &SiteConfig, query: &str, page: u64) -> Result<SearchResults, Status> {
+    let page = page.clamp(1, u64::MAX / SEARCH_PAGE_SIZE);
+    let terms = search_terms(query);
+    if terms.is_empty() {
+        return Ok(SearchResults { query: query.to_string(), page, total: 0, pages: 0, ranked: false, results: vec![] });
+    }
+    let skip = (page - 1) * SEARCH_PAGE_SIZE;
+    let mut ranked = config.search_text_index;
+    let mut found = if ranked { text_search(db, query, skip).await } else { regex_search(db, query, skip).await };
+    if matches!(&found, Err(error) if text_index_missing(error)) {
+        ranked = false;
+        found = regex_search(db, query, skip).await;
+    }
+    let (total, posts) = found.map_err(|_e| Status::InternalServerError)?;
+    let results = posts.into_iter().map(|(post, score)| {
+        let snippet = snippet(&post.content, &terms);
+        SearchHit { post, score, snippet }
+    }).collect();
+    let pages = total.div_ceil(SEARCH_PAGE_SIZE);
+    Ok(SearchResults { query: query.to_string(), page, total, pages, ranked, results })
+}
+
+// /blog/search?q=rocket&page=2
+#[get("/search?<q>&<page>")]
+async fn search_page(q: Option<&str>, page: Option<u64>, db: Connection<MainDatabase>, config: &State<SiteConfig>, locale: Locale) -> Result<Template, Status> {
+    let results = search(&db, config, q.unwrap_or_default(), page.unwrap_or(1)).await?;
+    let meta = PageMeta::new(config, "Search", format!("Search the posts on {}", config.site_name), uri!(blog::BASE, search_page(_, _)));
+    Ok(Template::render("blog/search", context! {search: results, meta, lang: locale.lang}))
+}
+
+// /blog/search.json?q=rocket&page=2
+#[get("/search.json?<q>&<page>")]
+async fn search_api(q: &str, page: Option<u64>, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Json<SearchResults>, Status> {
+    Ok(Json(search(&db, config, q, page.unwrap_or(1)).await?))
+}
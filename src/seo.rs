@@ -0,0 +1,94 @@
+// <head> metadata for blog pages: title, description, canonical url, OpenGraph/Twitter tags and JSON-LD
+// routes put a PageMeta in their template context under `meta` and base.html.tera renders it
+use serde::Serialize;
+use rocket::serde::json::serde_json::{self, json, Value};
+use crate::blog::{self, BlogPost};
+use crate::config::SiteConfig;
+use crate::databases::UserProfile;
+
+#[derive(Debug, Serialize)]
+pub struct PageMeta {
+    pub title: String,
+    pub site_name: String,
+    pub description: String,
+    pub canonical_url: String,
+    // OpenGraph type, "website" or "article"
+    pub og_type: &'static str,
+    // absolute url of the preview image
+    pub image: Option<String>,
+    // body of the application/ld+json script, already safe to embed in HTML
+    pub json_ld: Option<String>,
+    // other languages of the same page, rendered as hreflang links
+    pub alternates: Vec<Alternate>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alternate {
+    pub hreflang: String,
+    pub url: String,
+}
+
+impl PageMeta {
+    pub fn new(config: &SiteConfig, title: impl Into<String>, description: impl Into<String>, path: impl std::fmt::Display) -> PageMeta {
+        PageMeta {
+            title: title.into(),
+            site_name: config.site_name.clone(),
+            description: description.into(),
+            canonical_url: config.absolute_url(path),
+            og_type: "website",
+            image: None,
+            json_ld: None,
+            alternates: Vec::new(),
+        }
+    }
+
+    // listings are canonical on their first page, later pages point at themselves
+    pub fn listing(config: &SiteConfig, title: impl Into<String>, description: impl Into<String>, path: impl std::fmt::Display, page: u64) -> PageMeta {
+        match page {
+            0 | 1 => PageMeta::new(config, title, description, path),
+            page => PageMeta::new(config, title, description, format!("{path}?page={page}")),
+        }
+    }
+
+    pub fn for_post(config: &SiteConfig, post: &BlogPost, author: Option<&UserProfile>) -> PageMeta {
+        let mut meta = PageMeta::new(config, &post.title, post.excerpt(), uri!(blog::BASE, blog::blog_post(post.id())));
+        meta.og_type = "article";
+        meta.image = post.cover_image.as_ref().map(|image| absolute_image_url(config, image));
+        let mut posting = json!({
+            "@context": "https://schema.org",
+            "@type": "BlogPosting",
+            "headline": post.title,
+            "description": meta.description,
+            "url": meta.canonical_url,
+            "mainEntityOfPage": meta.canonical_url,
+            "datePublished": post.published_time.to_rfc3339(),
+            "dateModified": post.last_modified().to_rfc3339(),
+            "keywords": post.tags.join(", "),
+            "publisher": {"@type": "Organization", "name": config.site_name},
+        });
+        if let Some(image) = &meta.image {
+            posting["image"] = Value::from(image.as_str());
+        }
+        if let Some(author) = author {
+            posting["author"] = json!({
+                "@type": "Person",
+                "name": author.display_name,
+                "url": config.absolute_url(format!("{}/authors/{}", blog::BASE, rocket::http::RawStr::new(&author.username).percent_encode())),
+            });
+        }
+        meta.json_ld = Some(script_safe(&posting));
+        meta
+    }
+}
+
+fn absolute_image_url(config: &SiteConfig, image: &str) -> String {
+    if image.starts_with('/') {
+        return config.absolute_url(image);
+    }
+    image.to_string()
+}
+
+// JSON inside a <script> must not contain "</" or it could close the tag early
+fn script_safe(value: &Value) -> String {
+    serde_json::to_string(value).unwrap_or_default().replace("</", "<\\/")
+}
@@ -0,0 +1,187 @@
+// server-side login sessions, the session cookie only holds a random token and everything else lives in MongoDB
+// so logging out, demoting an admin or changing a password takes effect on the next request
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use rocket::{form::Form, Route, http::{Cookie, CookieJar, Status}, request::{FromRequest, Outcome, Request}, response::Redirect, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::FindOptions};
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::users::{self, AuthenticatedUser};
+use crate::utils::{random_token, token_hash};
+
+pub const SESSION_COOKIE: &str = "session";
+// sessions that are not used for this long are removed by a TTL index on last_seen
+pub const SESSION_IDLE_DAYS: i64 = 30;
+// last_seen is only written when it is older than this, so browsing doesn't cost a write per request
+const LAST_SEEN_INTERVAL_MINUTES: i64 = 5;
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+pub fn routes() -> Vec<Route> {
+    routes![logout, sessions_page, revoke_session, revoke_other_sessions]
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Session {
+    pub _id: ObjectId,
+    // sha-256 of the token in the cookie
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub created_time: bson::DateTime,
+    pub last_seen: bson::DateTime,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+// what the sessions page shows about each session
+#[derive(Serialize)]
+struct SessionInfo {
+    id: String,
+    created: String,
+    last_seen: String,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    current: bool,
+}
+
+impl Session {
+    fn info(&self, current: ObjectId) -> SessionInfo {
+        SessionInfo {
+            id: self._id.to_hex(),
+            created: self.created_time.to_chrono().format("%Y-%b-%d %H:%M UTC").to_string(),
+            last_seen: self.last_seen.to_chrono().format("%Y-%b-%d %H:%M UTC").to_string(),
+            user_agent: self.user_agent.clone(),
+            ip: self.ip.clone(),
+            current: self._id == current,
+        }
+    }
+}
+
+// the browser and address a login comes from, recorded on the session so users can recognise it
+pub struct SessionClient {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionClient {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_agent = request.headers().get_one("User-Agent").map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        let ip = request.client_ip().map(|ip| ip.to_string());
+        Outcome::Success(SessionClient { user_agent, ip })
+    }
+}
+
+// creates a session for a user who just proved who they are and hands its token to the browser
+pub async fn start_session(db: &mongodb::Client, jar: &CookieJar<'_>, user_id: ObjectId, client: &SessionClient) -> mongodb::error::Result<Session> {
+    let token = random_token(32);
+    let now = bson::DateTime::now();
+    let session = Session {
+        _id: ObjectId::new(),
+        token_hash: token_hash(&token),
+        user_id,
+        created_time: now,
+        last_seen: now,
+        user_agent: client.user_agent.clone(),
+        ip: client.ip.clone(),
+    };
+    db.sessions_coll().insert_one(&session, None).await?;
+    let mut cookie = Cookie::new(SESSION_COOKIE, token);
+    cookie.set_max_age(rocket::time::Duration::days(SESSION_IDLE_DAYS));
+    jar.add_private(cookie);
+    Ok(session)
+}
+
+// the live session for a token from the session cookie, bumping last_seen now and then
+pub async fn find_session(db: &mongodb::Client, token: &str, client: &SessionClient) -> mongodb::error::Result<Option<Session>> {
+    // the TTL monitor only runs every minute or so, don't accept sessions it hasn't got to yet
+    let idle_cutoff = bson::DateTime::from_chrono(Utc::now() - Duration::days(SESSION_IDLE_DAYS));
+    let mut session = match db.sessions_coll().find_one(doc! {"token_hash": token_hash(token), "last_seen": {"$gt": idle_cutoff}}, None).await? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let touch_cutoff = bson::DateTime::from_chrono(Utc::now() - Duration::minutes(LAST_SEEN_INTERVAL_MINUTES));
+    if session.last_seen < touch_cutoff {
+        session.last_seen = bson::DateTime::now();
+        session.ip = client.ip.clone().or(session.ip);
+        db.sessions_coll().update_one(
+            doc! {"_id": session._id},
+            doc! {"$set": {"last_seen": session.last_seen, "ip": session.ip.clone()}},
+            None,
+        ).await?;
+    }
+    Ok(Some(session))
+}
+
+// removes every session of a user except the one given, e.g. after a password change
+pub async fn revoke_sessions(db: &mongodb::Client, user_id: ObjectId, except: Option<ObjectId>) -> mongodb::error::Result<u64> {
+    let mut filter = doc! {"user_id": user_id};
+    if let Some(except) = except {
+        filter.insert("_id", doc! {"$ne": except});
+    }
+    Ok(db.sessions_coll().delete_many(filter, None).await?.deleted_count)
+}
+
+#[derive(FromForm)]
+struct SessionForm {
+    authenticity_token: String,
+}
+
+// a form post rather than a link so other sites can't log people out
+#[post("/logout", data = "<form>")]
+async fn logout(form: Form<SessionForm>, csrf_token: CsrfToken, user: Option<AuthenticatedUser>, jar: &CookieJar<'_>, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    if let Some(user) = user {
+        db.sessions_coll().delete_one(doc! {"_id": user.session_id}, None).await
+            .map_err(|_e| Status::InternalServerError)?;
+    }
+    jar.remove_private(Cookie::named(SESSION_COOKIE));
+    Ok(Redirect::to(uri!(users::login(_))))
+}
+
+// /account/sessions, every browser the user is logged in on
+#[get("/account/sessions")]
+async fn sessions_page(user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    let find_options = FindOptions::builder().sort(doc! {"last_seen": -1}).build();
+    let sessions: Vec<Session> = db.sessions_coll().find(doc! {"user_id": user.id}, find_options).await
+        .map_err(|_e| Status::InternalServerError)?
+        .try_collect().await.map_err(|_e| Status::InternalServerError)?;
+    let sessions: Vec<SessionInfo> = sessions.iter().map(|session| session.info(user.session_id)).collect();
+    Ok(Template::render("sessions", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        sessions,
+    }))
+}
+
+#[post("/account/sessions/<id>/revoke", data = "<form>")]
+async fn revoke_session(id: &str, form: Form<SessionForm>, csrf_token: CsrfToken, user: AuthenticatedUser, jar: &CookieJar<'_>, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let id = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    // matching on user_id too so nobody can end someone else's session
+    let deleted = db.sessions_coll().delete_one(doc! {"_id": id, "user_id": user.id}, None).await
+        .map_err(|_e| Status::InternalServerError)?.deleted_count;
+    if deleted == 0 {
+        return Err(Status::NotFound);
+    }
+    if id == user.session_id {
+        jar.remove_private(Cookie::named(SESSION_COOKIE));
+        return Ok(Redirect::to(uri!(users::login(_))));
+    }
+    Ok(Redirect::to(uri!(sessions_page)))
+}
+
+#[post("/account/sessions/revoke-others", data = "<form>")]
+async fn revoke_other_sessions(form: Form<SessionForm>, csrf_token: CsrfToken, user: AuthenticatedUser, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    revoke_sessions(&db, user.id, Some(user.session_id)).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(sessions_page)))
+}
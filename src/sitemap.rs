@@ -0,0 +1,125 @@
+// /sitemap.xml and /robots.txt, mounted at the root
+use rocket::{Route, State, http::{ContentType, Status}, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::FindOptions};
+use chrono::{DateTime, Utc};
+use bson::{doc, Document};
+use crate::blog::{self, newest_first, published_filter};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::utils::{remove_suffix, xml_escape, ConditionalBody};
+
+// the most urls a single sitemap file may contain according to sitemaps.org
+pub const MAX_SITEMAP_URLS: usize = 50_000;
+
+pub fn routes() -> Vec<Route> {
+    routes![sitemap, sitemap_part, robots]
+}
+
+struct SitemapEntry {
+    loc: String,
+    lastmod: Option<DateTime<Utc>>,
+}
+
+fn latest(a: Option<bson::DateTime>, b: Option<bson::DateTime>) -> Option<DateTime<Utc>> {
+    a.max(b).map(|time| time.to_chrono())
+}
+
+// every url we want indexed: the configured static pages, then tag pages, then published posts newest first
+async fn sitemap_entries(db: &mongodb::Client, config: &SiteConfig) -> mongodb::error::Result<Vec<SitemapEntry>> {
+    let mut entries: Vec<SitemapEntry> = config.sitemap_pages.iter()
+        .map(|page| SitemapEntry { loc: config.absolute_url(page), lastmod: None })
+        .collect();
+    let posts_coll = db.posts_coll().clone_with_type::<Document>();
+
+    // a tag page changes whenever one of its posts does
+    let pipeline = vec![
+        doc! {"$match": published_filter(None)},
+        doc! {"$unwind": "$tags"},
+        doc! {"$group": {"_id": "$tags", "published_time": {"$max": "$published_time"}, "updated_time": {"$max": "$updated_time"}}},
+        doc! {"$sort": {"_id": 1}},
+    ];
+    let tags: Vec<Document> = posts_coll.aggregate(pipeline, None).await?.try_collect().await?;
+    for tag in tags {
+        if let Ok(name) = tag.get_str("_id") {
+            entries.push(SitemapEntry {
+                loc: config.absolute_url(uri!(blog::BASE, blog::tag_posts(name, _))),
+                lastmod: latest(tag.get_datetime("published_time").ok().copied(), tag.get_datetime("updated_time").ok().copied()),
+            });
+        }
+    }
+
+    // only the fields needed for <loc> and <lastmod>, the content can be large
+    let find_options = FindOptions::builder()
+        .projection(doc! {"published_time": 1, "updated_time": 1})
+        .sort(newest_first())
+        .build();
+    let posts: Vec<Document> = posts_coll.find(published_filter(None), find_options).await?.try_collect().await?;
+    for post in posts {
+        if let Ok(id) = post.get_object_id("_id") {
+            entries.push(SitemapEntry {
+                loc: config.absolute_url(uri!(blog::BASE, blog::blog_post(id.to_hex()))),
+                lastmod: latest(post.get_datetime("published_time").ok().copied(), post.get_datetime("updated_time").ok().copied()),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+fn last_modified(entries: &[SitemapEntry]) -> DateTime<Utc> {
+    entries.iter().filter_map(|entry| entry.lastmod).max().unwrap_or_default()
+}
+
+fn urlset(entries: &[SitemapEntry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for entry in entries {
+        xml.push_str(&format!("<url><loc>{}</loc>", xml_escape(&entry.loc)));
+        if let Some(lastmod) = entry.lastmod {
+            xml.push_str(&format!("<lastmod>{}</lastmod>", lastmod.to_rfc3339()));
+        }
+        xml.push_str("</url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+fn sitemap_index(config: &SiteConfig, entries: &[SitemapEntry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for (i, part) in entries.chunks(MAX_SITEMAP_URLS).enumerate() {
+        let loc = config.absolute_url(uri!(sitemap_part(format!("{}.xml", i + 1))));
+        xml.push_str(&format!("<sitemap><loc>{}</loc><lastmod>{}</lastmod></sitemap>\n", xml_escape(&loc), last_modified(part).to_rfc3339()));
+    }
+    xml.push_str("</sitemapindex>\n");
+    xml
+}
+
+// a plain urlset while everything fits in one file, otherwise an index of /sitemaps/<n>.xml
+#[get("/sitemap.xml")]
+async fn sitemap(db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    let entries = sitemap_entries(&db, config).await.map_err(|_e| Status::InternalServerError)?;
+    let xml = if entries.len() > MAX_SITEMAP_URLS { sitemap_index(config, &entries) } else { urlset(&entries) };
+    Ok(ConditionalBody::new(xml, ContentType::XML, last_modified(&entries)))
+}
+
+// /sitemaps/2.xml, numbered from 1
+#[get("/sitemaps/<file>")]
+async fn sitemap_part(file: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    let part: usize = remove_suffix(file, ".xml").parse().map_err(|_e| Status::NotFound)?;
+    let entries = sitemap_entries(&db, config).await.map_err(|_e| Status::InternalServerError)?;
+    let part = entries.chunks(MAX_SITEMAP_URLS).nth(part.checked_sub(1).ok_or(Status::NotFound)?).ok_or(Status::NotFound)?;
+    Ok(ConditionalBody::new(urlset(part), ContentType::XML, last_modified(part)))
+}
+
+#[get("/robots.txt")]
+fn robots(config: &State<SiteConfig>) -> (ContentType, String) {
+    let mut robots = String::from("User-agent: *\n");
+    for path in &config.robots_disallow {
+        robots.push_str(&format!("Disallow: {path}\n"));
+    }
+    if config.robots_disallow.is_empty() {
+        robots.push_str("Disallow:\n");
+    }
+    robots.push_str(&format!("\nSitemap: {}\n", config.absolute_url(uri!(sitemap()))));
+    (ContentType::Plain, robots)
+}
@@ -0,0 +1,432 @@
+// optional TOTP (RFC 6238) two-factor authentication with single use recovery codes
+// users enroll on /account/two-factor by scanning a QR code and confirming a code, after that login_post
+// sends them to /login/two-factor before a session is started
+// wrong codes count as failed logins in login_attempts.rs, so a known password doesn't allow endless guessing
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use data_encoding::BASE32_NOPAD;
+use qrcode::{QrCode, render::svg};
+use rocket::{form::Form, Route, State, http::{Cookie, CookieJar, RawStr, Status}, response::Redirect};
+use rocket_db_pools::mongodb::{self, options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions}};
+use rocket_dyn_templates::{Template, context};
+use rocket_csrf::CsrfToken;
+use bson::{self, doc, oid::ObjectId};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils, User};
+use crate::i18n::Locale;
+use crate::login_attempts::{self, FailureReason};
+use crate::sessions::{SessionClient, start_session};
+use crate::users::{self, AdminUser, AuthenticatedUser, after_login, verify_password};
+use crate::usernames::normalize_username;
+use crate::utils::{random_token, token_hash};
+
+pub const CHALLENGE_COOKIE: &str = "login_challenge";
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// codes from one step either side are accepted to allow for clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+// 160 bits, the size RFC 4226 recommends
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+// the second login step has to be finished this soon after the password was checked
+const CHALLENGE_MINUTES: i64 = 5;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+// _id of the TwoFactorSettings document in site_settings
+const SETTINGS_ID: &str = "two_factor";
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        login_two_factor, login_two_factor_new, login_two_factor_post,
+        two_factor_page, two_factor_setup, two_factor_enable, two_factor_disable, regenerate_recovery_codes,
+        admin_requirement,
+    ]
+}
+
+// a user who got their password right and still has to enter a code, removed by a TTL index
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LoginChallenge {
+    pub _id: ObjectId,
+    // sha-256 of the token in the login_challenge cookie
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub next_page: Option<String>,
+    pub attempts: i32,
+    pub expires_time: bson::DateTime,
+}
+
+// site wide two-factor settings that admins change on /account/two-factor
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorSettings {
+    pub _id: String,
+    // admins can't use admin pages until they have set up two-factor, checked by the AdminUser guard
+    pub require_admin_two_factor: bool,
+}
+
+// require_admin_two_factor from Rocket.toml is only used until an admin has saved the setting
+pub async fn admin_two_factor_required(db: &mongodb::Client, config: &SiteConfig) -> mongodb::error::Result<bool> {
+    let settings = db.two_factor_settings_coll().find_one(doc! {"_id": SETTINGS_ID}, None).await?;
+    Ok(settings.map_or(config.require_admin_two_factor, |settings| settings.require_admin_two_factor))
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+// the time step a code belongs to, if it is valid for the secret around now
+fn totp_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.trim().replace(' ', "").parse().ok()?;
+    let current = now / TOTP_STEP_SECONDS;
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS).find(|step| *step >= 0 && hotp(&secret, *step as u64) == code)
+}
+
+fn new_secret() -> String {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+// xxxxx-xxxxx, easy to copy down
+fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES).map(|_| {
+        let code = random_token(5);
+        format!("{}-{}", &code[..5], &code[5..])
+    }).collect()
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase()
+}
+
+// what authenticator apps read from the QR code
+fn provisioning_uri(config: &SiteConfig, username: &str, secret: &str) -> String {
+    let issuer = RawStr::new(&config.site_name).percent_encode().to_string();
+    let account = RawStr::new(username).percent_encode().to_string();
+    format!("otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECONDS}")
+}
+
+fn qr_code_svg(data: &str) -> Option<String> {
+    let code = QrCode::new(data.as_bytes()).ok()?;
+    Some(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+// accepts a code once, a code that was already used can't be replayed within its window
+async fn accept_totp(db: &mongodb::Client, user: &User, code: &str) -> mongodb::error::Result<bool> {
+    let step = match user.totp_secret().and_then(|secret| totp_step(secret, code, Utc::now().timestamp())) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+    let filter = doc! {"_id": user.id(), "$or": [{"totp_last_step": null}, {"totp_last_step": {"$lt": step}}]};
+    let result = db.users_coll().update_one(filter, doc! {"$set": {"totp_last_step": step}}, None).await?;
+    Ok(result.matched_count == 1)
+}
+
+async fn accept_recovery_code(db: &mongodb::Client, user: &User, code: &str) -> mongodb::error::Result<bool> {
+    let hash = token_hash(&normalize_recovery_code(code));
+    let result = db.users_coll().update_one(doc! {"_id": user.id(), "recovery_codes": &hash}, doc! {"$pull": {"recovery_codes": &hash}}, None).await?;
+    Ok(result.modified_count == 1)
+}
+
+// called by login_post once the password checks out for a user with two-factor enabled
+pub async fn start_challenge(db: &mongodb::Client, jar: &CookieJar<'_>, user_id: ObjectId, next_page: Option<&str>) -> mongodb::error::Result<()> {
+    let token = random_token(32);
+    db.login_challenges_coll().insert_one(LoginChallenge {
+        _id: ObjectId::new(),
+        token_hash: token_hash(&token),
+        user_id,
+        next_page: next_page.map(String::from),
+        attempts: 0,
+        expires_time: bson::DateTime::from_chrono(Utc::now() + Duration::minutes(CHALLENGE_MINUTES)),
+    }, None).await?;
+    jar.add_private(Cookie::new(CHALLENGE_COOKIE, token));
+    Ok(())
+}
+
+#[get("/login/two-factor", rank = 1)]
+pub fn login_two_factor(csrf_token: CsrfToken, locale: Locale, jar: &CookieJar<'_>) -> Result<Template, Redirect> {
+    if jar.get_private(CHALLENGE_COOKIE).is_none() {
+        return Err(Redirect::to(uri!(users::login(_))));
+    }
+    Ok(Template::render("login-two-factor", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        lang: locale.lang,
+    }))
+}
+
+#[get("/login/two-factor", rank = 2)]
+fn login_two_factor_new() -> Redirect {
+    Redirect::to(uri!(login_two_factor))
+}
+
+#[derive(FromForm)]
+struct CodeData<'r> {
+    authenticity_token: String,
+    code: &'r str,
+}
+
+#[post("/login/two-factor", data = "<form>")]
+async fn login_two_factor_post(form: Form<CodeData<'_>>, csrf_token: CsrfToken, locale: Locale, jar: &CookieJar<'_>, client: SessionClient, db: Connection<MainDatabase>) -> Result<Result<Redirect, Template>, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Ok(Ok(Redirect::to(uri!(login_two_factor))));
+    }
+    let token = match jar.get_private(CHALLENGE_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Ok(Ok(Redirect::to(uri!(users::login(_))))),
+    };
+    // counting the attempt before checking it, so guesses made in parallel all use one up
+    let filter = doc! {"token_hash": token_hash(&token), "expires_time": {"$gt": bson::DateTime::now()}, "attempts": {"$lt": MAX_CHALLENGE_ATTEMPTS}};
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let challenge = db.login_challenges_coll().find_one_and_update(filter, doc! {"$inc": {"attempts": 1}}, options).await
+        .map_err(|_e| Status::InternalServerError)?;
+    let challenge = match challenge {
+        Some(challenge) => challenge,
+        // expired or out of attempts, start again from the password
+        None => {
+            jar.remove_private(Cookie::named(CHALLENGE_COOKIE));
+            return Ok(Ok(Redirect::to(uri!(users::login(_)))));
+        },
+    };
+    let user = match db.users_coll().find_one(doc! {"_id": challenge.user_id}, None).await.map_err(|_e| Status::InternalServerError)? {
+        Some(user) => user,
+        None => {
+            jar.remove_private(Cookie::named(CHALLENGE_COOKIE));
+            return Ok(Ok(Redirect::to(uri!(users::login(_)))));
+        },
+    };
+    // the same counts as the password step, every new challenge would otherwise come with fresh attempts
+    let username = normalize_username(user.username());
+    if login_attempts::blocked_until(&db, &username, client.ip.as_deref()).await.map_err(|_e| Status::InternalServerError)?.is_some() {
+        login_attempts::record_failure(&db, &username, Some(user.id()), &client, FailureReason::Throttled).await.map_err(|_e| Status::InternalServerError)?;
+        return Ok(Err(code_error(csrf_token, locale, "login.throttled")));
+    }
+    let accepted = accept_totp(&db, &user, form.code).await.map_err(|_e| Status::InternalServerError)?
+        || accept_recovery_code(&db, &user, form.code).await.map_err(|_e| Status::InternalServerError)?;
+    if !accepted {
+        login_attempts::record_failure(&db, &username, Some(user.id()), &client, FailureReason::WrongCode).await.map_err(|_e| Status::InternalServerError)?;
+        return Ok(Err(code_error(csrf_token, locale, "two_factor.invalid_code")));
+    }
+    login_attempts::clear_failures(&db, &username).await.map_err(|_e| Status::InternalServerError)?;
+    db.login_challenges_coll().delete_one(doc! {"_id": challenge._id}, None).await.map_err(|_e| Status::InternalServerError)?;
+    jar.remove_private(Cookie::named(CHALLENGE_COOKIE));
+    start_session(&db, jar, user.id(), &client).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Ok(after_login(challenge.next_page.as_deref())))
+}
+
+fn code_error(csrf_token: CsrfToken, locale: Locale, error: &str) -> Template {
+    Template::render("login-two-factor", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        error,
+        lang: locale.lang,
+    })
+}
+
+async fn find_account(db: &mongodb::Client, user: &AuthenticatedUser) -> Result<User, Status> {
+    db.users_coll().find_one(doc! {"_id": user.id}, None).await
+        .map_err(|_e| Status::InternalServerError)?
+        .ok_or(Status::Unauthorized)
+}
+
+// /account/two-factor, turn two-factor on or off and manage recovery codes
+#[get("/account/two-factor")]
+async fn two_factor_page(user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    let account = find_account(&db, &user).await?;
+    let admin_requirement = admin_two_factor_required(&db, config).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Template::render("two-factor", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        enabled: account.totp_secret().is_some(),
+        recovery_codes_left: account.recovery_codes_left(),
+        required: user.admin && admin_requirement,
+        admin: user.admin,
+        admin_requirement,
+    }))
+}
+
+#[derive(FromForm)]
+struct RequirementData {
+    authenticity_token: String,
+    // a checkbox, missing when unticked
+    required: bool,
+}
+
+// turns the two-factor requirement for admin accounts on or off, it applies from the next request
+#[post("/account/two-factor/admin-requirement", data = "<form>")]
+async fn admin_requirement(form: Form<RequirementData>, _user: AdminUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let options = UpdateOptions::builder().upsert(true).build();
+    db.two_factor_settings_coll().update_one(doc! {"_id": SETTINGS_ID}, doc! {"$set": {"require_admin_two_factor": form.required}}, options).await
+        .map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(two_factor_page)))
+}
+
+#[derive(FromForm)]
+struct TokenData {
+    authenticity_token: String,
+}
+
+// starts enrolling with a new secret, it only takes effect once a code from it is confirmed
+#[post("/account/two-factor/setup", data = "<form>")]
+async fn two_factor_setup(form: Form<TokenData>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let account = find_account(&db, &user).await?;
+    if account.totp_secret().is_some() {
+        return Err(Status::Conflict);
+    }
+    let secret = new_secret();
+    db.users_coll().update_one(doc! {"_id": user.id}, doc! {"$set": {"totp_pending_secret": &secret}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    let uri = provisioning_uri(config, &user.username, &secret);
+    Ok(Template::render("two-factor", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        enrolling: true,
+        secret,
+        qr_code: qr_code_svg(&uri),
+        uri,
+    }))
+}
+
+#[post("/account/two-factor/enable", data = "<form>")]
+async fn two_factor_enable(form: Form<CodeData<'_>>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let account = find_account(&db, &user).await?;
+    let secret = account.totp_pending_secret().ok_or(Status::Conflict)?.to_string();
+    let step = match totp_step(&secret, form.code, Utc::now().timestamp()) {
+        Some(step) => step,
+        None => {
+            let uri = provisioning_uri(config, &user.username, &secret);
+            return Ok(Template::render("two-factor", context! {
+                authenticity_token: csrf_token.authenticity_token(),
+                enrolling: true,
+                qr_code: qr_code_svg(&uri),
+                secret,
+                uri,
+                error: "two_factor.setup_mismatch",
+            }));
+        },
+    };
+    let recovery_codes = new_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| token_hash(code)).collect();
+    db.users_coll().update_one(
+        doc! {"_id": user.id, "totp_pending_secret": &secret},
+        doc! {"$set": {"totp_secret": &secret, "totp_last_step": step, "recovery_codes": hashes}, "$unset": {"totp_pending_secret": ""}},
+        None,
+    ).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Template::render("two-factor", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        enabled: true,
+        recovery_codes,
+        recovery_codes_left: RECOVERY_CODES,
+    }))
+}
+
+#[derive(FromForm)]
+struct DisableData<'r> {
+    authenticity_token: String,
+    password: &'r str,
+}
+
+#[post("/account/two-factor/disable", data = "<form>")]
+async fn two_factor_disable(form: Form<DisableData<'_>>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    if user.admin && admin_two_factor_required(&db, config).await.map_err(|_e| Status::InternalServerError)? {
+        return Err(Status::Forbidden);
+    }
+    let account = find_account(&db, &user).await?;
+    // someone who only has the session shouldn't be able to weaken the account
+    if !verify_password(&account, form.password) {
+        return Err(Status::Unauthorized);
+    }
+    db.users_coll().update_one(
+        doc! {"_id": user.id},
+        doc! {"$unset": {"totp_secret": "", "totp_pending_secret": "", "totp_last_step": ""}, "$set": {"recovery_codes": []}},
+        None,
+    ).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(two_factor_page)))
+}
+
+// new recovery codes replace all of the old ones, needs a current code so a stolen session can't take them
+#[post("/account/two-factor/recovery-codes", data = "<form>")]
+async fn regenerate_recovery_codes(form: Form<CodeData<'_>>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let account = find_account(&db, &user).await?;
+    if !accept_totp(&db, &account, form.code).await.map_err(|_e| Status::InternalServerError)? {
+        return Ok(Template::render("two-factor", context! {
+            authenticity_token: csrf_token.authenticity_token(),
+            enabled: account.totp_secret().is_some(),
+            recovery_codes_left: account.recovery_codes_left(),
+            error: "two_factor.recovery_mismatch",
+        }));
+    }
+    let recovery_codes = new_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| token_hash(code)).collect();
+    db.users_coll().update_one(doc! {"_id": user.id}, doc! {"$set": {"recovery_codes": hashes}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    Ok(Template::render("two-factor", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        enabled: true,
+        recovery_codes,
+        recovery_codes_left: RECOVERY_CODES,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the shared secret from RFC 4226 appendix D and RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), *code, "counter {counter}");
+        }
+    }
+
+    // RFC 6238 lists 8 digit SHA-1 codes, ours are their last 6 digits
+    #[test]
+    fn totp_matches_rfc_6238() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let vectors = [(59, "287082"), (1111111109, "081804"), (1111111111, "050471"), (1234567890, "005924"), (2000000000, "279037"), (20000000000, "353130")];
+        for (time, code) in vectors {
+            assert_eq!(totp_step(&secret, code, time), Some(time / TOTP_STEP_SECONDS), "time {time}");
+        }
+    }
+
+    #[test]
+    fn totp_accepts_one_step_of_skew() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        // 287082 is the code for step 1 (59 seconds)
+        assert_eq!(totp_step(&secret, "287082", 29), Some(1));
+        assert_eq!(totp_step(&secret, "287082", 89), Some(1));
+        assert_eq!(totp_step(&secret, "287 082", 59), Some(1));
+        assert_eq!(totp_step(&secret, "287082", 119), None);
+        assert_eq!(totp_step(&secret, "287082", 150), None);
+    }
+
+    #[test]
+    fn totp_rejects_bad_input() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        assert_eq!(totp_step(&secret, "abcdef", 59), None);
+        assert_eq!(totp_step("not base32!", "287082", 59), None);
+    }
+}
@@ -0,0 +1,310 @@
+// image and attachment uploads for blog posts, mounted under blog::BASE
+use std::io::Cursor;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use rocket_csrf::CsrfToken;
+use rocket::{form::Form, fs::TempFile, Route, State, http::{ContentType, Status}};
+use rocket::futures::{AsyncReadExt as _, AsyncWriteExt as _};
+use rocket::tokio::{self, fs, io::AsyncReadExt};
+use rocket_db_pools::mongodb::{self, options::GridFsBucketOptions, gridfs::GridFsBucket};
+use rocket_dyn_templates::{Template, context};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use bson::{self, doc, oid::ObjectId};
+use crate::blog::{self, PostAuthor};
+use crate::config::{SiteConfig, UploadStorage};
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::users::AdminUser;
+use crate::utils::ConditionalBody;
+
+// uploads never change once stored so clients may cache them forever
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+// browsers must go by the stored type, a PDF or anything else served inline is never sniffed into HTML
+const NOSNIFF: (&str, &str) = ("X-Content-Type-Options", "nosniff");
+const JPEG_QUALITY: u8 = 85;
+
+pub fn routes() -> Vec<Route> {
+    routes![new_upload, upload_post, serve_upload, serve_thumbnail]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Pdf,
+}
+
+impl FileKind {
+    // identifies a file by its magic bytes instead of trusting the name or content type sent by the browser
+    pub fn sniff(bytes: &[u8]) -> Option<FileKind> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(FileKind::Png)
+        } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(FileKind::Jpeg)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(FileKind::Gif)
+        } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+            Some(FileKind::Webp)
+        } else if bytes.starts_with(b"%PDF-") {
+            Some(FileKind::Pdf)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            FileKind::Png => "image/png",
+            FileKind::Jpeg => "image/jpeg",
+            FileKind::Gif => "image/gif",
+            FileKind::Webp => "image/webp",
+            FileKind::Pdf => "application/pdf",
+        }
+    }
+
+    fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            FileKind::Png => Some(ImageFormat::Png),
+            FileKind::Jpeg => Some(ImageFormat::Jpeg),
+            FileKind::Gif => Some(ImageFormat::Gif),
+            FileKind::Webp => Some(ImageFormat::WebP),
+            FileKind::Pdf => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Upload {
+    pub _id: ObjectId,
+    // the name on the uploader's computer, only used for alt text and link titles
+    pub original_name: String,
+    pub mime: String,
+    pub size: u64,
+    pub storage: UploadStorage,
+    pub thumbnail_mime: Option<String>,
+    pub uploader: PostAuthor,
+    pub created_time: bson::DateTime,
+}
+
+// what the upload page shows after an upload
+#[derive(Debug, Serialize)]
+pub struct UploadInfo {
+    pub id: String,
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+    // paste into a post to reference the upload
+    pub markdown: String,
+}
+
+impl Upload {
+    pub fn url(&self) -> String {
+        uri!(blog::BASE, serve_upload(self._id.to_hex())).to_string()
+    }
+
+    pub fn info(&self) -> UploadInfo {
+        let url = self.url();
+        let thumbnail_url = self.thumbnail_mime.as_ref().map(|_| uri!(blog::BASE, serve_thumbnail(self._id.to_hex())).to_string());
+        let label = self.original_name.replace(['[', ']'], "");
+        let markdown = match &thumbnail_url {
+            // link the thumbnail to the full size image
+            Some(thumbnail_url) => format!("[![{label}]({thumbnail_url})]({url})"),
+            None if self.mime.starts_with("image/") => format!("![{label}]({url})"),
+            None => format!("[{label}]({url})"),
+        };
+        UploadInfo { id: self._id.to_hex(), url, thumbnail_url, markdown }
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    // the client sent something we do not accept, the message is safe to show
+    Rejected(&'static str),
+    Io(std::io::Error),
+    Database(mongodb::error::Error),
+    Image(image::ImageError),
+}
+
+impl UploadError {
+    pub fn status(&self) -> Status {
+        match self {
+            UploadError::Rejected(_) => Status::UnprocessableEntity,
+            error => {
+                println!("{error:?}");
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(error: std::io::Error) -> UploadError {
+        UploadError::Io(error)
+    }
+}
+
+impl From<mongodb::error::Error> for UploadError {
+    fn from(error: mongodb::error::Error) -> UploadError {
+        UploadError::Database(error)
+    }
+}
+
+impl From<image::ImageError> for UploadError {
+    fn from(error: image::ImageError) -> UploadError {
+        UploadError::Image(error)
+    }
+}
+
+#[derive(FromForm)]
+struct UploadData<'r> {
+    authenticity_token: String,
+    file: TempFile<'r>,
+}
+
+fn bucket(db: &mongodb::Client) -> GridFsBucket {
+    db.app_db().gridfs_bucket(GridFsBucketOptions::builder().bucket_name("uploads".to_string()).build())
+}
+
+fn thumbnail_name(id: ObjectId) -> String {
+    format!("{}_thumbnail", id.to_hex())
+}
+
+async fn write_blob(db: &mongodb::Client, config: &SiteConfig, storage: UploadStorage, name: &str, bytes: &[u8]) -> Result<(), UploadError> {
+    match storage {
+        UploadStorage::Disk => {
+            fs::create_dir_all(&config.upload_dir).await?;
+            fs::write(Path::new(&config.upload_dir).join(name), bytes).await?;
+        },
+        UploadStorage::GridFs => {
+            let mut stream = bucket(db).open_upload_stream(name, None);
+            stream.write_all(bytes).await?;
+            stream.close().await?;
+        },
+    }
+    Ok(())
+}
+
+async fn read_blob(db: &mongodb::Client, config: &SiteConfig, storage: UploadStorage, name: &str) -> Result<Vec<u8>, UploadError> {
+    let mut bytes = Vec::new();
+    match storage {
+        UploadStorage::Disk => bytes = fs::read(Path::new(&config.upload_dir).join(name)).await?,
+        UploadStorage::GridFs => {
+            let mut stream = bucket(db).open_download_stream_by_name(name, None).await?;
+            stream.read_to_end(&mut bytes).await?;
+        },
+    }
+    Ok(bytes)
+}
+
+fn encode(image: &DynamicImage, kind: FileKind) -> Result<(Vec<u8>, &'static str), image::ImageError> {
+    let mut bytes = Vec::new();
+    // photos stay JPEG, everything else becomes a PNG
+    if kind == FileKind::Jpeg {
+        image.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+        return Ok((bytes, FileKind::Jpeg.mime()));
+    }
+    image.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)?;
+    Ok((bytes, FileKind::Png.mime()))
+}
+
+// re-encodes and/or thumbnails an image, returns the bytes to store and the optional thumbnail with its mime type
+fn process_image(bytes: Vec<u8>, kind: FileKind, reencode: bool, thumbnail_size: Option<u32>) -> Result<(Vec<u8>, Option<(Vec<u8>, &'static str)>), image::ImageError> {
+    let format = match kind.image_format() {
+        Some(format) => format,
+        None => return Ok((bytes, None)),
+    };
+    let image = image::load_from_memory_with_format(&bytes, format)?;
+    let thumbnail = match thumbnail_size {
+        Some(size) => Some(encode(&image.thumbnail(size, size), kind)?),
+        None => None,
+    };
+    // GIFs are left alone so animations survive, WebP so we do not have to encode it
+    if reencode && matches!(kind, FileKind::Png | FileKind::Jpeg) {
+        return Ok((encode(&image, kind)?.0, thumbnail));
+    }
+    Ok((bytes, thumbnail))
+}
+
+// validates, processes and stores an upload from the form below
+pub async fn store_upload(db: &mongodb::Client, config: &SiteConfig, file: &TempFile<'_>, uploader: PostAuthor) -> Result<Upload, UploadError> {
+    if file.len() == 0 {
+        return Err(UploadError::Rejected("the file is empty"));
+    }
+    if file.len() > config.max_upload_bytes {
+        return Err(UploadError::Rejected("the file is too large"));
+    }
+    let mut bytes = Vec::with_capacity(file.len() as usize);
+    let reader = file.open().await?;
+    tokio::pin!(reader);
+    reader.read_to_end(&mut bytes).await?;
+    let kind = FileKind::sniff(&bytes).ok_or(UploadError::Rejected("only PNG, JPEG, GIF, WebP and PDF files are allowed"))?;
+
+    let reencode = config.upload_reencode;
+    let thumbnail_size = if config.upload_thumbnails { Some(config.thumbnail_size) } else { None };
+    // decoding images is CPU heavy so keep it off the async workers
+    let (bytes, thumbnail) = tokio::task::spawn_blocking(move || process_image(bytes, kind, reencode, thumbnail_size)).await
+        .map_err(|e| UploadError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?
+        .map_err(|_e| UploadError::Rejected("the image could not be read"))?;
+
+    let id = ObjectId::new();
+    write_blob(db, config, config.upload_storage, &id.to_hex(), &bytes).await?;
+    if let Some((thumbnail_bytes, _)) = &thumbnail {
+        write_blob(db, config, config.upload_storage, &thumbnail_name(id), thumbnail_bytes).await?;
+    }
+    let upload = Upload {
+        _id: id,
+        original_name: file.raw_name().map(|name| name.dangerous_unsafe_unsanitized_raw().to_string()).unwrap_or_default(),
+        mime: kind.mime().to_string(),
+        size: bytes.len() as u64,
+        storage: config.upload_storage,
+        thumbnail_mime: thumbnail.map(|(_, mime)| mime.to_string()),
+        uploader,
+        created_time: bson::DateTime::now(),
+    };
+    db.uploads_coll().insert_one(&upload, None).await?;
+    Ok(upload)
+}
+
+async fn find_upload(db: &mongodb::Client, id: &str) -> Result<Upload, Status> {
+    let oid = ObjectId::parse_str(id).map_err(|_e| Status::NotFound)?;
+    db.uploads_coll().find_one(doc! {"_id": oid}, None).await.map_err(|_e| Status::InternalServerError)?.ok_or(Status::NotFound)
+}
+
+fn content_type(mime: &str) -> ContentType {
+    ContentType::parse_flexible(mime).unwrap_or(ContentType::Binary)
+}
+
+#[get("/uploads/new")]
+fn new_upload(_user: AdminUser, csrf_token: CsrfToken) -> Template {
+    Template::render("blog/upload", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+    })
+}
+
+#[post("/uploads", data = "<form>")]
+async fn upload_post(form: Form<UploadData<'_>>, user: AdminUser, csrf_token: CsrfToken, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<Template, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let rendered = match store_upload(&db, config, &form.file, PostAuthor::from(&user.0)).await {
+        Ok(upload) => context! {authenticity_token: csrf_token.authenticity_token(), upload: upload.info()},
+        Err(UploadError::Rejected(error)) => context! {authenticity_token: csrf_token.authenticity_token(), error},
+        Err(error) => return Err(error.status()),
+    };
+    Ok(Template::render("blog/upload", rendered))
+}
+
+#[get("/uploads/<id>")]
+async fn serve_upload(id: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    let upload = find_upload(&db, id).await?;
+    let bytes = read_blob(&db, config, upload.storage, &upload._id.to_hex()).await.map_err(|_e| Status::NotFound)?;
+    Ok(ConditionalBody::new(bytes, content_type(&upload.mime), upload.created_time.to_chrono()).cache_control(IMMUTABLE).header(NOSNIFF.0, NOSNIFF.1))
+}
+
+#[get("/uploads/<id>/thumbnail")]
+async fn serve_thumbnail(id: &str, db: Connection<MainDatabase>, config: &State<SiteConfig>) -> Result<ConditionalBody, Status> {
+    let upload = find_upload(&db, id).await?;
+    let mime = upload.thumbnail_mime.as_deref().ok_or(Status::NotFound)?;
+    let bytes = read_blob(&db, config, upload.storage, &thumbnail_name(upload._id)).await.map_err(|_e| Status::NotFound)?;
+    Ok(ConditionalBody::new(bytes, content_type(mime), upload.created_time.to_chrono()).cache_control(IMMUTABLE).header(NOSNIFF.0, NOSNIFF.1))
+}
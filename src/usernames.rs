@@ -0,0 +1,113 @@
+// what a username can be, used by sign-up, social logins and everywhere a typed username is looked up
+// usernames are stored normalized (NFKC then lowercased) so Admin, ADMIN and Ａｄｍｉｎ are all the same account,
+// after that only a-z, 0-9, '.', '_' and '-' are allowed, which also leaves no room for look-alike characters
+// display_name is where people can put capitals, accents and emoji
+use unicode_normalization::UnicodeNormalization;
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+// names that look official or clash with pages, compared without separators so ad-min and a.d.m.i.n count too
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "root", "system", "sysadmin", "moderator", "mod", "staff", "support", "help",
+    "security", "webmaster", "postmaster", "hostmaster", "noreply", "mail", "email", "www",
+    "login", "logout", "signup", "signin", "register", "account", "accounts", "profile", "settings",
+    "api", "auth", "oauth", "blog", "static", "uploads", "feed", "rss", "atom", "sitemap", "robots",
+    "null", "undefined", "anonymous", "self", "owner",
+];
+
+pub fn allowed_character(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-')
+}
+
+// the form a username is stored and compared in, lowercasing can undo NFKC so it is applied again after
+pub fn normalize_username(username: &str) -> String {
+    username.trim().nfkc().collect::<String>().to_lowercase().nfkc().collect()
+}
+
+// the message key from locales/ for what is wrong with an already normalized username, None when it can be used
+pub fn username_problem(username: &str) -> Option<&'static str> {
+    if username.is_empty() {
+        return Some("signup.username_required");
+    }
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Some("signup.username_length");
+    }
+    if !username.chars().all(allowed_character) {
+        return Some("signup.username_characters");
+    }
+    // so they read as names in urls and mentions, not ._. or -1
+    let alphanumeric_ends = username.starts_with(|c: char| c.is_ascii_alphanumeric()) && username.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if !alphanumeric_ends {
+        return Some("signup.username_characters");
+    }
+    let bare: String = username.chars().filter(char::is_ascii_alphanumeric).collect();
+    if RESERVED_USERNAMES.contains(&bare.as_str()) {
+        return Some("signup.username_reserved");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizing_folds_case_and_width() {
+        assert_eq!(normalize_username("Admin"), "admin");
+        assert_eq!(normalize_username("ADMIN"), normalize_username("admin"));
+        // fullwidth letters from CJK keyboards
+        assert_eq!(normalize_username("Ａｄｍｉｎ"), "admin");
+        assert_eq!(normalize_username("  someone \t"), "someone");
+    }
+
+    #[test]
+    fn normalizing_applies_compatibility_forms() {
+        // the ligature ﬁ and the circled ⓐ only look like ordinary letters
+        assert_eq!(normalize_username("ﬁsh"), "fish");
+        assert_eq!(normalize_username("ⓐlice"), "alice");
+        // ANGSTROM SIGN and the letter Å are one character after NFKC
+        assert_eq!(normalize_username("\u{212B}"), normalize_username("\u{c5}"));
+    }
+
+    #[test]
+    fn accepts_ordinary_usernames() {
+        let longest = "x".repeat(MAX_USERNAME_LENGTH);
+        for username in ["bob", "alice.smith", "dev_42", "a-b", "abc", longest.as_str()] {
+            assert_eq!(username_problem(username), None, "{username}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert_eq!(username_problem(""), Some("signup.username_required"));
+        assert_eq!(username_problem("ab"), Some("signup.username_length"));
+        assert_eq!(username_problem(&"x".repeat(MAX_USERNAME_LENGTH + 1)), Some("signup.username_length"));
+    }
+
+    #[test]
+    fn rejects_characters_outside_the_allowed_set() {
+        for username in ["bob smith", "bob@example", "bøb", "аdmin", "bob😀", "Bob"] {
+            assert_eq!(username_problem(username), Some("signup.username_characters"), "{username}");
+        }
+        // accented letters are still accented after normalizing, so they stay out
+        assert_eq!(username_problem(&normalize_username("\u{212B}ngstrom")), Some("signup.username_characters"));
+    }
+
+    #[test]
+    fn rejects_separators_at_the_ends() {
+        for username in [".bob", "bob.", "_bob", "bob-", "._."] {
+            assert_eq!(username_problem(username), Some("signup.username_characters"), "{username}");
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_names_with_or_without_separators() {
+        for username in ["admin", "root", "ad-min", "a.d.m.i.n", "sys_admin", "support"] {
+            assert_eq!(username_problem(username), Some("signup.username_reserved"), "{username}");
+        }
+        assert_eq!(username_problem(&normalize_username("Ａｄｍｉｎ")), Some("signup.username_reserved"));
+        assert_eq!(username_problem("admins"), None);
+    }
+}
@@ -1,15 +1,29 @@
 // TODO
-use crate::databases::{Connection, MainDatabase, User, DatabaseUtils};
-use bson::doc;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::databases::{Connection, MainDatabase, User, UserProfile, DatabaseUtils};
+use bson::{doc, oid::ObjectId};
 use argon2::{
     password_hash::{
         rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString
     },
-    Argon2
+    Algorithm, Argon2, Params, Version
 };
 use rocket_dyn_templates::{Template, context};
 use rocket_csrf::CsrfToken;
-use rocket::{form::Form, Route, http::{Status, Cookie, CookieJar}, response::Redirect, request::{Outcome, FromRequest}};
+use rocket::{form::Form, Catcher, Route, State, http::{Status, CookieJar}, response::{Flash, Redirect}, request::{FlashMessage, Outcome, FromRequest}, futures::TryStreamExt};
+use rocket_db_pools::{mongodb::{self, options::{Collation, CollationStrength, CountOptions}}, Database};
+use crate::i18n::{Catalogs, Locale};
+use crate::config::SiteConfig;
+use crate::email_verification::{send_verification, valid_email, verify_email_page};
+use crate::mailer::Mailer;
+use crate::sessions::{SESSION_COOKIE, SessionClient, find_session, start_session};
+use crate::two_factor::{admin_two_factor_required, login_two_factor, start_challenge};
+use crate::oauth::provider_names;
+use crate::login_attempts::{self, FailureReason};
+use crate::password_policy::password_problem;
+use crate::usernames::{normalize_username, username_problem};
+use crate::utils::{is_duplicate_key, is_local_path};
 // TODO: use emails as the username in the future
 
 #[derive(FromForm)]
@@ -20,51 +34,257 @@ struct LoginData<'r> {
     password: &'r str,
 }
 
-struct _UserGuard {
-    username: String,
-    admin: bool
+// the logged in user, loaded from MongoDB through the session started by login_post
+// usage : (user: AuthenticatedUser, ...) or Option<AuthenticatedUser> for pages that also work logged out
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub id: ObjectId,
+    pub username: String,
+    pub admin: bool,
+    // the session this request was made with, see sessions.rs
+    pub session_id: ObjectId,
+    // has TOTP set up, see two_factor.rs
+    pub two_factor: bool,
+}
+
+// an AuthenticatedUser that is an admin, fails with 403 for everyone else
+#[derive(Clone, Debug)]
+pub struct AdminUser(pub AuthenticatedUser);
+
+impl std::ops::Deref for AdminUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+// set when a request failed because nobody is logged in, so the 401 catcher knows to send them to the login page
+struct LoginRequired(bool);
+
+// set when an admin without two-factor was turned away because admins are required to use it, see two_factor.rs
+struct TwoFactorRequired(bool);
+
+// looked up once per request no matter how many guards ask
+async fn current_user(request: &rocket::Request<'_>) -> &Option<AuthenticatedUser> {
+    request.local_cache_async(async {
+        let token = request.cookies().get_private(SESSION_COOKIE)?;
+        let db = MainDatabase::fetch(request.rocket())?;
+        let client = SessionClient::from_request(request).await.succeeded()?;
+        match load_user(&db.0, token.value(), &client).await {
+            Ok(user) => user,
+            Err(error) => {
+                println!("{error:?}");
+                None
+            },
+        }
+    }).await
+}
+
+async fn load_user(db: &mongodb::Client, token: &str, client: &SessionClient) -> mongodb::error::Result<Option<AuthenticatedUser>> {
+    let session = match find_session(db, token, client).await? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let user = db.users_coll().find_one(doc!{"_id": session.user_id, "email_verified": {"$ne": false}}, None).await?;
+    Ok(user.map(|user| AuthenticatedUser {
+        id: user.id(),
+        username: user.username().to_string(),
+        admin: user.admin(),
+        session_id: session._id,
+        two_factor: user.totp_secret().is_some(),
+    }))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        match current_user(request).await {
+            Some(user) => Outcome::Success(user.clone()),
+            None => {
+                request.local_cache(|| LoginRequired(true));
+                Outcome::Failure((Status::Unauthorized, ()))
+            },
+        }
+    }
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for _UserGuard {
-    type Error = bool;
-
-    async fn from_request(request: &'r rocket::Request<'_>) ->  Outcome<Self, Self::Error> {
-        let username = request.cookies().get_private("username").map(|c| c.value().to_string());
-        let admin = request.cookies().get_private("admin").map(
-            |v| match v.value() {
-            "true" => true,
-            _ => false
-        });
-        if let (Some(username), Some(admin)) = (username, admin) {
-            return Outcome::Success(_UserGuard{username, admin});
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        match AuthenticatedUser::from_request(request).await {
+            Outcome::Success(user) if user.admin && user.two_factor => Outcome::Success(AdminUser(user)),
+            // the setting is only looked up for admins who would be turned away by it
+            Outcome::Success(user) if user.admin => {
+                let required = match (MainDatabase::fetch(request.rocket()), request.rocket().state::<SiteConfig>()) {
+                    (Some(db), Some(config)) => admin_two_factor_required(&db.0, config).await,
+                    _ => Ok(false),
+                };
+                match required {
+                    Ok(true) => {
+                        request.local_cache(|| TwoFactorRequired(true));
+                        Outcome::Failure((Status::Forbidden, ()))
+                    },
+                    Ok(false) => Outcome::Success(AdminUser(user)),
+                    Err(error) => {
+                        println!("{error:?}");
+                        Outcome::Failure((Status::InternalServerError, ()))
+                    },
+                }
+            },
+            Outcome::Success(_) => Outcome::Failure((Status::Forbidden, ())),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(forward) => Outcome::Forward(forward),
         }
-        Outcome::Failure((Status::Unauthorized, false))
     }
 }
 
+// HTML routes that need a login send the visitor to it and back, API routes have their own JSON catchers
+#[catch(401)]
+fn unauthorized(request: &rocket::Request) -> Result<Redirect, Status> {
+    if request.local_cache(|| LoginRequired(false)).0 {
+        return Ok(Redirect::to(uri!(login(Some(request.uri().to_string())))));
+    }
+    Err(Status::Unauthorized)
+}
+
+// admins who have to set up two-factor first are sent to do that
+#[catch(403)]
+fn forbidden(request: &rocket::Request) -> Result<Redirect, Status> {
+    if request.local_cache(|| TwoFactorRequired(false)).0 {
+        return Ok(Redirect::to("/account/two-factor"));
+    }
+    Err(Status::Forbidden)
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![unauthorized, forbidden]
+}
+
 #[post("/login", data = "<form>")]
-async fn login_post(form: Form<LoginData<'_>>, csrf_token: CsrfToken, db: Connection<MainDatabase>, jar: &CookieJar<'_>, ) -> Result<Redirect, Status> {
+async fn login_post(form: Form<LoginData<'_>>, csrf_token: CsrfToken, db: Connection<MainDatabase>, jar: &CookieJar<'_>, client: SessionClient, config: &State<SiteConfig>) -> Result<Result<Redirect, Flash<Redirect>>, Status> {
     if csrf_token.verify(&form.authenticity_token).is_err() {
-        return Ok(Redirect::to(uri!(login(form.next_page))));
+        return Ok(Ok(Redirect::to(uri!(login(form.next_page)))));
+    }
+    // failures are counted against the normalized name so Admin and admin share one count
+    let username = normalize_username(form.username);
+    // too many recent failures for this username or address, the password isn't even checked
+    if login_attempts::blocked_until(&db, &username, client.ip.as_deref()).await.map_err(|_e| Status::InternalServerError)?.is_some() {
+        login_attempts::record_failure(&db, &username, None, &client, FailureReason::Throttled).await.map_err(|_e| Status::InternalServerError)?;
+        return Ok(Err(Flash::error(Redirect::to(uri!(login(form.next_page))), "login.throttled")));
     }
     // verify login here
-    if let Some(existing_user) = db.users_coll().find_one(doc!{"username": &form.username}, None).await.map_err(|_e| Status::InternalServerError)? {
-        let parsed_hash = PasswordHash::new(existing_user.password_hash()).map_err(|_e| Status::InternalServerError)?;
-        if Argon2::default().verify_password(&form.password.as_bytes(), &parsed_hash).is_ok() {
-            // user is authenticated, add username and admin status to private cookie
-            // https://rocket.rs/v0.5-rc/guide/requests/#private-cookies
-            jar.add_private(Cookie::new("username", existing_user.username().to_string()));
-            jar.add_private(Cookie::new("admin", existing_user.admin().to_string()));
-            if let Some(next_page) = form.next_page {
-                return Ok(Redirect::to(next_page.to_string()));
+    let existing_user = find_user(&db, form.username).await.map_err(|_e| Status::InternalServerError)?;
+    if let Some(existing_user) = &existing_user {
+        if verify_password(existing_user, form.password) {
+            // the password is only known right now, so this is when a hash made with old argon2 settings can be redone
+            if needs_rehash(existing_user, config) {
+                if let Ok(password_hash) = hash_password(form.password, config) {
+                    db.users_coll().update_one(doc!{"_id": existing_user.id()}, doc!{"$set": {"password": password_hash}}, None).await
+                        .map_err(|_e| Status::InternalServerError)?;
+                }
             }
-            return Ok(Redirect::to(uri!(authenticated_sample_route)));
+            // the password is right but the account can't be used until its email is confirmed
+            if !existing_user.email_verified() {
+                return Ok(Ok(Redirect::to(uri!(verify_email_page(Some(existing_user.username()), form.next_page, _)))));
+            }
+            // with two-factor on, the session only starts once a code is entered on /login/two-factor
+            // and the failures stay until then, so knowing the password doesn't buy unlimited code guesses
+            if existing_user.totp_secret().is_some() {
+                start_challenge(&db, jar, existing_user.id(), form.next_page).await.map_err(|_e| Status::InternalServerError)?;
+                return Ok(Ok(Redirect::to(uri!(login_two_factor))));
+            }
+            login_attempts::clear_failures(&db, &username).await.map_err(|_e| Status::InternalServerError)?;
+            // user is authenticated, the private cookie only holds the session token, AuthenticatedUser loads the rest
+            // https://rocket.rs/v0.5-rc/guide/requests/#private-cookies
+            start_session(&db, jar, existing_user.id(), &client).await.map_err(|_e| Status::InternalServerError)?;
+            return Ok(Ok(after_login(form.next_page)));
         }
-     }
-     // username or password incorrect
-    // Flash::error(Redirect::to(uri!(login(form.next_page)), "invalid username or password"))
-    Ok(Redirect::to(uri!(login(form.next_page))))
+    } else {
+        // hashing anyway so an unknown username takes as long to turn down as a wrong password
+        hash_password(form.password, config).ok();
+    }
+    // username or password incorrect, the same answer either way
+    let reason = if existing_user.is_some() { FailureReason::WrongPassword } else { FailureReason::UnknownUser };
+    login_attempts::record_failure(&db, &username, existing_user.map(|user| user.id()), &client, reason).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Err(Flash::error(Redirect::to(uri!(login(form.next_page))), "login.failed")))
+}
+
+// where a finished login goes, shared with the two-factor step, passkey and social logins
+// next comes from the query string, so only local paths
+pub fn after_login_url(next_page: Option<&str>) -> String {
+    if let Some(next_page) = next_page.filter(|next| is_local_path(next)) {
+        return next_page.to_string();
+    }
+    uri!(authenticated_sample_route).to_string()
+}
+
+pub fn after_login(next_page: Option<&str>) -> Redirect {
+    Redirect::to(after_login_url(next_page))
+}
+
+pub fn verify_password(user: &User, password: &str) -> bool {
+    match PasswordHash::new(user.password_hash()) {
+        Ok(parsed_hash) => Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok(),
+        Err(_e) => false,
+    }
+}
+
+// Argon2id with the costs from Rocket.toml, verifying doesn't need this since a hash carries its own costs
+fn argon2(config: &SiteConfig) -> Result<Argon2<'static>, argon2::Error> {
+    let params = Params::new(config.argon2_memory_kib, config.argon2_iterations, config.argon2_parallelism, None)?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+// salted Argon2 hash for storing in User.password, shared by sign-up and password resets
+pub fn hash_password(password: &str, config: &SiteConfig) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = argon2(config)?;
+    Ok(argon2.hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+// true when the user's hash was made with other argon2 settings than the current ones
+pub fn needs_rehash(user: &User, config: &SiteConfig) -> bool {
+    let parsed_hash = match PasswordHash::new(user.password_hash()) {
+        Ok(parsed_hash) => parsed_hash,
+        Err(_e) => return false,
+    };
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => parsed_hash.algorithm != Algorithm::Argon2id.ident()
+            || params.m_cost() != config.argon2_memory_kib
+            || params.t_cost() != config.argon2_iterations
+            || params.p_cost() != config.argon2_parallelism,
+        Err(_e) => true,
+    }
+}
+
+// the exact name first since accounts made before usernames were normalized can have capitals, then what it normalizes to
+pub async fn find_user(db: &mongodb::Client, username: &str) -> mongodb::error::Result<Option<User>> {
+    if let Some(user) = db.users_coll().find_one(doc!{"username": username}, None).await? {
+        return Ok(Some(user));
+    }
+    let normalized = normalize_username(username);
+    if normalized == username {
+        return Ok(None);
+    }
+    db.users_coll().find_one(doc!{"username": normalized}, None).await
+}
+
+// also catches older accounts that only differ in case, which the unique index on username can't
+async fn username_taken(db: &mongodb::Client, username: &str) -> mongodb::error::Result<bool> {
+    let collation = Collation::builder().locale("en").strength(CollationStrength::Secondary).build();
+    let options = CountOptions::builder().collation(collation).build();
+    Ok(db.users_coll().count_documents(doc!{"username": username}, options).await? > 0)
+}
+
+// public profiles of the given users keyed by username, used to show authors next to posts
+pub async fn find_profiles(db: &mongodb::Client, usernames: Vec<String>) -> mongodb::error::Result<HashMap<String, UserProfile>> {
+    let users: Vec<User> = db.users_coll().find(doc!{"username": {"$in": usernames}}, None).await?.try_collect().await?;
+    Ok(users.iter().map(|user| (user.username().to_string(), user.profile())).collect())
 }
 
 #[get("/post-login")]
@@ -73,10 +293,13 @@ fn authenticated_sample_route() -> String {
 }
 
 #[get("/login?<next>", rank=1)]
-fn login(csrf_token: CsrfToken, next: Option<&str>) -> Template {
+pub fn login(csrf_token: CsrfToken, locale: Locale, config: &State<SiteConfig>, flash: Option<FlashMessage<'_>>, next: Option<&str>) -> Template {
     Template::render("login", context! {
         authenticity_token: csrf_token.authenticity_token(),
-        next_page: next
+        next_page: next,
+        error: flash.map(|flash| flash.message().to_string()),
+        providers: provider_names(config),
+        lang: locale.lang,
     })
 }
 
@@ -91,17 +314,19 @@ struct SignUpData<'b> {
     authenticity_token: String,
     next_page: Option<&'b str>,
     username: &'b str,
+    email: &'b str,
     password: &'b str,
-    _email_code: Option<&'b str>, // TODO
 }
 
 
 #[get("/sign-up?<next>", rank = 1)]
-fn sign_up(csrf_token: CsrfToken, next: Option<&str>) -> Template {
+fn sign_up(csrf_token: CsrfToken, locale: Locale, config: &State<SiteConfig>, next: Option<&str>) -> Template {
     // TODO: add another method that redirects if already logged in?
     Template::render("sign-up", context! {
         authenticity_token: csrf_token.authenticity_token(),
-        next_page: next
+        next_page: next,
+        password_min_length: config.password_min_length,
+        lang: locale.lang,
     })
 }
 
@@ -111,32 +336,104 @@ fn sign_up_new(next: Option<&str>) -> Redirect {
 }
 
 #[post("/sign-up", data = "<form>")]
-async fn post_sign_up(db: Connection<MainDatabase>, csrf_token: CsrfToken, form: Form<SignUpData<'_>>) -> Result<Redirect, Template> {
+async fn post_sign_up(db: Connection<MainDatabase>, csrf_token: CsrfToken, locale: Locale, form: Form<SignUpData<'_>>, mailer: &State<Box<dyn Mailer>>, config: &State<SiteConfig>, catalogs: &State<Arc<Catalogs>>) -> Result<Redirect, Template> {
     if csrf_token.verify(&form.authenticity_token).is_err() {
         return Ok(Redirect::to(uri!(sign_up(form.next_page))));
     }
+    let username = normalize_username(form.username);
+    let email = form.email.trim().to_lowercase();
+    // the form is shown again with what they typed and a message key from locales/ under each field that was wrong
+    let sign_up_errors = |errors: HashMap<&str, &str>| Template::render("sign-up", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        next_page: form.next_page,
+        username: form.username,
+        email: &email,
+        errors,
+        password_min_length: config.password_min_length,
+        lang: &locale.lang,
+    });
+    let mut errors = HashMap::new();
+    if let Some(problem) = username_problem(&username) {
+        errors.insert("username", problem);
+    } else if username_taken(&db, &username).await.map_err(|_e| sign_up_errors(HashMap::from([("form", "signup.failed")])))? {
+        errors.insert("username", "signup.username_taken");
+    }
+    if !valid_email(&email) {
+        errors.insert("email", "signup.invalid_email");
+    }
+    if let Some(problem) = password_problem(config, form.password, &[&username, &email]).await {
+        errors.insert("password", problem);
+    }
+    if !errors.is_empty() {
+        return Err(sign_up_errors(errors));
+    }
     // create user by first hashing the salted password
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2.hash_password(form.password.as_bytes(), &salt).unwrap().to_string();
-    if db.users_coll().insert_one(
-        User::new(
-            form.username,
-            &password_hash,
-        ), None).await.is_err() {
-        return Err(Template::render("sign-up", context! {
-            authenticity_token: csrf_token.authenticity_token(),
-            next_page: form.next_page,
-            error: "username already exists"
-        }));
+    let password_hash = match hash_password(form.password, config) {
+        Ok(password_hash) => password_hash,
+        Err(error) => {
+            println!("{error}");
+            return Err(sign_up_errors(HashMap::from([("form", "signup.failed")])));
+        },
     };
-    // TODO: email confirmation (requires rendering template with different variables)
-    if let Some(next_page) = form.next_page {
-        return Ok(Redirect::to(next_page.to_string()));
+    let user = User::new(&username, &password_hash, &email);
+    match db.users_coll().insert_one(&user, None).await {
+        Ok(_) => {},
+        // both are unique, work out which one clashed
+        Err(error) if is_duplicate_key(&error) => {
+            let email_taken = db.users_coll().count_documents(doc!{"email": &email}, None).await.unwrap_or(0) > 0;
+            return Err(sign_up_errors(if email_taken { HashMap::from([("email", "signup.email_taken")]) } else { HashMap::from([("username", "signup.username_taken")]) }));
+        },
+        Err(error) => {
+            println!("{error}");
+            return Err(sign_up_errors(HashMap::from([("form", "signup.failed")])));
+        },
+    }
+    // the account exists either way, if the mail didn't go out they can ask for it again
+    if let Err(error) = send_verification(&db, mailer.inner().as_ref(), config, catalogs, &user, &locale.lang).await {
+        println!("{error:?}");
+    }
+    Ok(Redirect::to(uri!(verify_email_page(Some(user.username()), form.next_page, _))))
+}
+
+#[derive(FromForm)]
+struct ProfileData<'r> {
+    authenticity_token: String,
+    display_name: &'r str,
+    bio: &'r str,
+}
+
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+pub const MAX_BIO_LENGTH: usize = 1000;
+
+#[get("/account/profile")]
+async fn profile(user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Template, Redirect> {
+    let account = find_user(&db, &user.username).await.ok().flatten();
+    let profile = account.as_ref().map(User::profile);
+    // the form gets the saved name, not the username profile() falls back to, so saving it doesn't copy the username over
+    let display_name = account.as_ref().and_then(User::display_name);
+    Ok(Template::render("profile", context! {
+        authenticity_token: csrf_token.authenticity_token(),
+        profile,
+        display_name,
+        admin: user.admin,
+    }))
+}
+
+#[post("/account/profile", data = "<form>")]
+async fn profile_post(form: Form<ProfileData<'_>>, user: AuthenticatedUser, csrf_token: CsrfToken, db: Connection<MainDatabase>) -> Result<Redirect, Status> {
+    if csrf_token.verify(&form.authenticity_token).is_err() {
+        return Err(Status::Unauthorized);
+    }
+    let display_name = form.display_name.trim();
+    let bio = form.bio.trim();
+    if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH || bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(Status::UnprocessableEntity);
     }
-    Ok(Redirect::to(uri!(login(form.next_page))))
+    db.users_coll().update_one(doc!{"_id": user.id}, doc!{"$set": {"display_name": display_name, "bio": bio}}, None).await
+        .map_err(|_e| Status::InternalServerError)?;
+    Ok(Redirect::to(uri!(profile)))
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![login, login_new, login_post, authenticated_sample_route, sign_up, sign_up_new, post_sign_up]
+    routes![login, login_new, login_post, authenticated_sample_route, sign_up, sign_up_new, post_sign_up, profile, profile_post]
 }
@@ -1,5 +1,13 @@
 use reqwest::Client;
 use rocket::State;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::io::Cursor;
+use rocket_db_pools::mongodb::error::{Error, ErrorKind, WriteFailure};
 
 
 pub fn remove_suffix<'a>(s: &'a str, suffix: &str) -> &'a str {
@@ -9,6 +17,154 @@ pub fn remove_suffix<'a>(s: &'a str, suffix: &str) -> &'a str {
     }
 }
 
+// true for a path on this site that is safe to redirect to after a login or a language switch
+// browsers read /\host like //host and drop tabs and newlines, so those can't sneak another host in either
+pub fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\') && !path.chars().any(char::is_control)
+}
+
+// a write that broke a unique index, E11000
+pub fn is_duplicate_key(error: &Error) -> bool {
+    matches!(*error.kind, ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000)
+}
+
 pub async fn text_request(client: &State<Client>, url: &str) -> Result<String, reqwest::Error> {
     client.get(url).send().await?.text().await
 }
+
+// escapes text so it can be placed inside XML elements and attributes
+pub fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // control characters other than tab and newlines are not allowed in XML 1.0
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {},
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// escapes user input so it matches literally inside a MongoDB $regex
+pub fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}-/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// the first max_chars characters of text with whitespace collapsed, cut at a word boundary
+pub fn excerpt(text: &str, max_chars: usize) -> String {
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= max_chars {
+        return collapsed;
+    }
+    let cut: String = collapsed.chars().take(max_chars).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if space > 0 => &cut[..space],
+        _ => cut.as_str(),
+    };
+    format!("{}…", cut.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+}
+
+// lowercase ascii letters and digits separated by single dashes e.g. "Hello, World!" -> "hello-world"
+pub fn slugify(s: &str) -> String {
+    let mut slug = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+// formats a time for the Last-Modified header (IMF-fixdate)
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// a hex encoded secret from the OS random number generator, for session ids and emailed links
+pub fn random_token(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buffer);
+    to_hex(&buffer)
+}
+
+// tokens are stored hashed so a leaked database can't be used to log in
+pub fn token_hash(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+// a body that supports conditional GETs through ETag and Last-Modified
+// clients that send a matching If-None-Match or a recent enough If-Modified-Since get a 304
+pub struct ConditionalBody {
+    body: Vec<u8>,
+    content_type: ContentType,
+    etag: String,
+    last_modified: DateTime<Utc>,
+    cache_control: &'static str,
+    headers: Vec<(&'static str, &'static str)>,
+}
+
+impl ConditionalBody {
+    pub fn new<B: Into<Vec<u8>>>(body: B, content_type: ContentType, last_modified: DateTime<Utc>) -> ConditionalBody {
+        let body = body.into();
+        let digest = Sha256::digest(&body);
+        let etag = format!("\"{}\"", to_hex(&digest[..16]));
+        ConditionalBody { body, content_type, etag, last_modified, cache_control: "public, max-age=300", headers: Vec::new() }
+    }
+
+    // e.g. "public, max-age=31536000, immutable" for content that never changes
+    pub fn cache_control(mut self, cache_control: &'static str) -> ConditionalBody {
+        self.cache_control = cache_control;
+        self
+    }
+
+    // sent with the body, e.g. ("X-Content-Type-Options", "nosniff")
+    pub fn header(mut self, name: &'static str, value: &'static str) -> ConditionalBody {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ConditionalBody {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        // If-None-Match takes precedence over If-Modified-Since (RFC 9110 13.2.2)
+        let not_modified = match request.headers().get_one("If-None-Match") {
+            Some(etags) => etags.split(',').map(str::trim).any(|etag| etag == "*" || etag.trim_start_matches("W/") == self.etag),
+            None => request.headers().get_one("If-Modified-Since")
+                .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+                .map_or(false, |since| self.last_modified.timestamp() <= since.timestamp()),
+        };
+        let mut response = Response::build();
+        response
+            .raw_header("ETag", self.etag)
+            .raw_header("Last-Modified", http_date(self.last_modified))
+            .raw_header("Cache-Control", self.cache_control);
+        if not_modified {
+            return response.status(Status::NotModified).ok();
+        }
+        for (name, value) in self.headers {
+            response.raw_header(name, value);
+        }
+        response
+            .header(self.content_type)
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
@@ -0,0 +1,208 @@
+// post view counting, the popular posts page and daily view stats for admins, mounted under blog::BASE
+// a view counts once per visitor session per post per day and is added to the post's view_count and to
+// a per post per day rollup so trends can be read without keeping every raw view
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use data_encoding::HEXLOWER;
+use rocket::{Route, State, http::{Cookie, Status}, request::{FromRequest, Outcome, Request}, futures::TryStreamExt};
+use rocket_db_pools::mongodb::{self, options::{FindOptions, UpdateOptions}};
+use rocket_dyn_templates::{Template, context};
+use bson::{self, doc, oid::ObjectId, Document};
+use crate::blog::{self, BlogPost, published_filter};
+use crate::config::SiteConfig;
+use crate::databases::{Connection, MainDatabase, DatabaseUtils};
+use crate::i18n::Locale;
+use crate::seo::PageMeta;
+use crate::users::AdminUser;
+use crate::utils::is_duplicate_key;
+
+pub const POPULAR_POSTS: i64 = 20;
+pub const DEFAULT_STATS_DAYS: u64 = 30;
+pub const MAX_STATS_DAYS: u64 = 366;
+// user agents containing any of these (lowercased) are not counted
+const BOT_MARKERS: [&str; 12] = ["bot", "crawl", "spider", "slurp", "preview", "headless", "curl", "wget", "python", "go-http-client", "java/", "feed"];
+
+pub fn routes() -> Vec<Route> {
+    routes![popular_posts, view_stats]
+}
+
+// marks that a visitor has already been counted for a post today, removed by a TTL index after a couple of days
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ViewMark {
+    // <visitor>:<post id>:<day>
+    pub _id: String,
+    pub created_time: bson::DateTime,
+}
+
+// views of one post on one day
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ViewDay {
+    // <post id>:<day>
+    pub _id: String,
+    pub post_id: ObjectId,
+    // YYYY-MM-DD in UTC, sorts the same as the date
+    pub day: String,
+    pub views: i64,
+}
+
+// who is viewing, identified by an id in a private session cookie
+pub struct Visitor {
+    id: String,
+    bot: bool,
+}
+
+fn is_bot(user_agent: Option<&str>) -> bool {
+    match user_agent {
+        Some(user_agent) => {
+            let user_agent = user_agent.to_lowercase();
+            BOT_MARKERS.iter().any(|marker| user_agent.contains(marker))
+        },
+        None => true,
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Visitor {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let bot = is_bot(request.headers().get_one("User-Agent"));
+        let id = match request.cookies().get_private("visitor_id") {
+            Some(cookie) => cookie.value().to_string(),
+            None => {
+                let id = visitor_hash(request);
+                // no expiry so it only lasts for the browser session
+                if !bot {
+                    request.cookies().add_private(Cookie::new("visitor_id", id.clone()));
+                }
+                id
+            },
+        };
+        Outcome::Success(Visitor { id, bot })
+    }
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+// the id for a request without the cookie, a browser that never keeps cookies would otherwise count again on every reload
+// keyed with secret_key so the stored id can't be turned back into an address, and it changes every UTC day
+fn visitor_hash(request: &Request<'_>) -> String {
+    let key = request.rocket().figment().extract_inner::<String>("secret_key").unwrap_or_default();
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    let ip = request.client_ip().map(|ip| ip.to_string()).unwrap_or_default();
+    let user_agent = request.headers().get_one("User-Agent").unwrap_or_default();
+    mac.update(format!("{ip}\n{user_agent}\n{}", today()).as_bytes());
+    HEXLOWER.encode(&mac.finalize().into_bytes())
+}
+
+// counts a view of the post unless it comes from a bot or was already counted today
+pub async fn record_view(db: &mongodb::Client, post: &BlogPost, visitor: &Visitor) -> mongodb::error::Result<()> {
+    if visitor.bot {
+        return Ok(());
+    }
+    let day = today();
+    let mark = ViewMark { _id: format!("{}:{}:{}", visitor.id, post.id(), day), created_time: bson::DateTime::now() };
+    match db.view_marks_coll().insert_one(&mark, None).await {
+        Ok(_) => {},
+        Err(error) if is_duplicate_key(&error) => return Ok(()),
+        Err(error) => return Err(error),
+    }
+    db.posts_coll().update_one(doc! {"_id": post._id}, doc! {"$inc": {"view_count": 1}}, None).await?;
+    db.view_days_coll().update_one(
+        doc! {"_id": format!("{}:{}", post.id(), day)},
+        doc! {"$inc": {"views": 1}, "$setOnInsert": {"post_id": post._id, "day": &day}},
+        UpdateOptions::builder().upsert(true).build(),
+    ).await?;
+    Ok(())
+}
+
+// rows of the rollup aggregations below
+#[derive(Debug, Deserialize)]
+struct PostViews {
+    _id: ObjectId,
+    views: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DailyViews {
+    day: String,
+    views: i64,
+    // number of different posts viewed that day
+    posts: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct PopularPost {
+    post: BlogPost,
+    views: i64,
+}
+
+fn first_day(days: u64) -> String {
+    (Utc::now() - Duration::days(days.saturating_sub(1) as i64)).format("%Y-%m-%d").to_string()
+}
+
+// most viewed published posts over the last `days` days, from the daily rollups
+async fn popular_since(db: &mongodb::Client, days: u64) -> mongodb::error::Result<Vec<PopularPost>> {
+    let pipeline = vec![
+        doc! {"$match": {"day": {"$gte": first_day(days)}}},
+        doc! {"$group": {"_id": "$post_id", "views": {"$sum": "$views"}}},
+        doc! {"$sort": {"views": -1}},
+        // extra room for posts that have since been unpublished or deleted
+        doc! {"$limit": POPULAR_POSTS * 2},
+    ];
+    let totals: Vec<Document> = db.view_days_coll().aggregate(pipeline, None).await?.try_collect().await?;
+    let totals: Vec<PostViews> = totals.into_iter().map(bson::from_document).collect::<Result<_, _>>()?;
+    let mut filter = published_filter(None);
+    filter.insert("_id", doc! {"$in": totals.iter().map(|total| total._id).collect::<Vec<ObjectId>>()});
+    let posts: Vec<BlogPost> = db.posts_coll().find(filter, None).await?.try_collect().await?;
+    let mut posts: HashMap<ObjectId, BlogPost> = posts.into_iter().map(|post| (post._id, post)).collect();
+    Ok(totals.into_iter()
+        .filter_map(|total| Some(PopularPost { post: posts.remove(&total._id)?, views: total.views }))
+        .take(POPULAR_POSTS as usize)
+        .collect())
+}
+
+// most viewed published posts of all time
+async fn popular_all_time(db: &mongodb::Client) -> mongodb::error::Result<Vec<PopularPost>> {
+    let mut filter = published_filter(None);
+    filter.insert("view_count", doc! {"$gt": 0});
+    let find_options = FindOptions::builder().sort(doc! {"view_count": -1, "published_time": -1}).limit(POPULAR_POSTS).build();
+    let posts: Vec<BlogPost> = db.posts_coll().find(filter, find_options).await?.try_collect().await?;
+    Ok(posts.into_iter().map(|post| PopularPost { views: post.view_count as i64, post }).collect())
+}
+
+// /blog/popular for all time, /blog/popular?days=7 for the last week
+#[get("/popular?<days>")]
+async fn popular_posts(days: Option<u64>, db: Connection<MainDatabase>, config: &State<SiteConfig>, locale: Locale) -> Result<Template, Status> {
+    let days = days.map(|days| days.clamp(1, MAX_STATS_DAYS));
+    let popular = match days {
+        Some(days) => popular_since(&db, days).await,
+        None => popular_all_time(&db).await,
+    }.map_err(|_e| Status::InternalServerError)?;
+    let meta = PageMeta::new(config, "Popular posts", format!("The most read posts on {}", config.site_name), uri!(blog::BASE, popular_posts(_)));
+    Ok(Template::render("blog/popular", context! {popular, days, meta, lang: locale.lang}))
+}
+
+// /blog/stats?days=90, total views per day and the top posts over the period
+#[get("/stats?<days>")]
+async fn view_stats(days: Option<u64>, _user: AdminUser, db: Connection<MainDatabase>) -> Result<Template, Status> {
+    let days = days.unwrap_or(DEFAULT_STATS_DAYS).clamp(1, MAX_STATS_DAYS);
+    let pipeline = vec![
+        doc! {"$match": {"day": {"$gte": first_day(days)}}},
+        doc! {"$group": {"_id": "$day", "views": {"$sum": "$views"}, "posts": {"$sum": 1}}},
+        doc! {"$project": {"_id": 0, "day": "$_id", "views": 1, "posts": 1}},
+        doc! {"$sort": {"day": 1}},
+    ];
+    let daily: Vec<Document> = db.view_days_coll().aggregate(pipeline, None).await
+        .map_err(|_e| Status::InternalServerError)?
+        .try_collect().await.map_err(|_e| Status::InternalServerError)?;
+    let daily: Vec<DailyViews> = daily.into_iter().map(bson::from_document).collect::<Result<_, _>>().map_err(|_e| Status::InternalServerError)?;
+    let total: i64 = daily.iter().map(|day| day.views).sum();
+    let top = popular_since(&db, days).await.map_err(|_e| Status::InternalServerError)?;
+    Ok(Template::render("blog/stats", context! {days, daily, total, top}))
+}
@@ -0,0 +1,102 @@
+// browser side of the passkey ceremonies in src/passkeys.rs
+// the server sends and expects binary fields as base64url strings, WebAuthn wants ArrayBuffers
+const PASSKEY_API = "/api/passkeys";
+
+function base64urlToBuffer(value) {
+    const base64 = value.replace(/-/g, "+").replace(/_/g, "/").padEnd(Math.ceil(value.length / 4) * 4, "=");
+    return Uint8Array.from(atob(base64), c => c.charCodeAt(0)).buffer;
+}
+
+function bufferToBase64url(buffer) {
+    const bytes = String.fromCharCode(...new Uint8Array(buffer));
+    return btoa(bytes).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+async function postJson(path, body) {
+    const response = await fetch(PASSKEY_API + path, {
+        method: "POST",
+        headers: {"Content-Type": "application/json"},
+        credentials: "same-origin",
+        body: JSON.stringify(body === undefined ? {} : body),
+    });
+    const data = await response.json();
+    if (!response.ok) {
+        throw new Error(data.error || response.statusText);
+    }
+    return data;
+}
+
+// adds a passkey to the logged in account, resolves with {id, name, created, last_used}
+async function registerPasskey(name) {
+    const options = await postJson("/register/start");
+    const publicKey = options.publicKey;
+    publicKey.challenge = base64urlToBuffer(publicKey.challenge);
+    publicKey.user.id = base64urlToBuffer(publicKey.user.id);
+    (publicKey.excludeCredentials || []).forEach(credential => credential.id = base64urlToBuffer(credential.id));
+    const credential = await navigator.credentials.create({publicKey});
+    return postJson("/register/finish", {
+        name,
+        credential: {
+            id: credential.id,
+            rawId: bufferToBase64url(credential.rawId),
+            type: credential.type,
+            response: {
+                attestationObject: bufferToBase64url(credential.response.attestationObject),
+                clientDataJSON: bufferToBase64url(credential.response.clientDataJSON),
+            },
+            extensions: credential.getClientExtensionResults(),
+        },
+    });
+}
+
+// logs in with one of the user's passkeys and follows the redirect the server sends back
+async function loginWithPasskey(username, next) {
+    const options = await postJson("/login/start", {username, next: next || null});
+    const publicKey = options.publicKey;
+    publicKey.challenge = base64urlToBuffer(publicKey.challenge);
+    (publicKey.allowCredentials || []).forEach(credential => credential.id = base64urlToBuffer(credential.id));
+    const credential = await navigator.credentials.get({publicKey});
+    const result = await postJson("/login/finish", {
+        id: credential.id,
+        rawId: bufferToBase64url(credential.rawId),
+        type: credential.type,
+        response: {
+            authenticatorData: bufferToBase64url(credential.response.authenticatorData),
+            clientDataJSON: bufferToBase64url(credential.response.clientDataJSON),
+            signature: bufferToBase64url(credential.response.signature),
+            userHandle: credential.response.userHandle ? bufferToBase64url(credential.response.userHandle) : null,
+        },
+        extensions: credential.getClientExtensionResults(),
+    });
+    // the server only sends local paths, checked here too so nothing else is ever navigated to
+    const redirect = result.redirect;
+    const local = redirect.startsWith("/") && !redirect.startsWith("//") && !redirect.includes("\\");
+    window.location.assign(local ? redirect : "/");
+}
+
+// wires up any passkey buttons on the page, see templates/login.html.tera and templates/passkeys.html.tera
+document.addEventListener("DOMContentLoaded", () => {
+    const showError = (element, error) => {
+        if (element) {
+            element.textContent = error.message;
+        }
+    };
+    const loginButton = document.getElementById("passkey-login");
+    if (loginButton) {
+        loginButton.addEventListener("click", () => {
+            const username = document.querySelector("input[name=username]").value;
+            const next = loginButton.dataset.next;
+            loginWithPasskey(username, next).catch(error => showError(document.getElementById("passkey-error"), error));
+        });
+    }
+    const registerForm = document.getElementById("passkey-register");
+    if (registerForm) {
+        registerForm.addEventListener("submit", event => {
+            event.preventDefault();
+            const name = registerForm.querySelector("input[name=name]").value;
+            registerPasskey(name)
+                .then(() => window.location.reload())
+                .catch(error => showError(document.getElementById("passkey-error"), error));
+        });
+    }
+});